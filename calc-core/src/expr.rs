@@ -0,0 +1,323 @@
+//! Infix expression parser and evaluator: turns text into an `Expr` tree
+//! and evaluates it in any numeric mode.
+//!
+//! Supports numbers, `+ - * / ^`, parentheses and unary minus/plus.
+//! `^` is right associative and binds tighter than unary minus, so
+//! `-2^2` is `-4` and `2^3^2` is `2^9`.
+
+use std::fmt;
+use serde_derive::Serialize;
+
+use crate::numeric::{self, Context, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+}
+
+impl BinaryOp {
+    pub const ALL: [BinaryOp; 5] = [BinaryOp::Add, BinaryOp::Sub, BinaryOp::Mul, BinaryOp::Div, BinaryOp::Pow];
+
+    pub fn symbol(self) -> char {
+        match self {
+            BinaryOp::Add => '+',
+            BinaryOp::Sub => '-',
+            BinaryOp::Mul => '*',
+            BinaryOp::Div => '/',
+            BinaryOp::Pow => '^',
+        }
+    }
+
+    pub fn from_symbol(symbol: char) -> Option<BinaryOp> {
+        match symbol {
+            '+' => Some(BinaryOp::Add),
+            '-' => Some(BinaryOp::Sub),
+            '*' => Some(BinaryOp::Mul),
+            '/' => Some(BinaryOp::Div),
+            '^' => Some(BinaryOp::Pow),
+            _ => None,
+        }
+    }
+
+    pub fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Add | BinaryOp::Sub => 1,
+            BinaryOp::Mul | BinaryOp::Div => 2,
+            BinaryOp::Pow => 4,
+        }
+    }
+
+    fn is_right_assoc(self) -> bool {
+        self == BinaryOp::Pow
+    }
+
+    pub fn apply(self, lhs: f64, rhs: f64) -> f64 {
+        match self {
+            BinaryOp::Add => lhs + rhs,
+            BinaryOp::Sub => lhs - rhs,
+            BinaryOp::Mul => lhs * rhs,
+            BinaryOp::Div => lhs / rhs,
+            BinaryOp::Pow => lhs.powf(rhs),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnaryOp {
+    Neg,
+    Plus,
+}
+
+/// Unary operators sit between `* /` and `^`.
+const UNARY_PRECEDENCE: u8 = 3;
+
+/// How deeply operators and parentheses may nest. Parsing, evaluating and
+/// serializing all recurse once per level, so this bounds their stack use.
+pub const MAX_NESTING: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Expr {
+    Number {
+        #[serde(serialize_with = "numeric::serialize_f64")]
+        value: f64,
+        /// The number as written, for the exact numeric modes.
+        #[serde(skip)]
+        literal: String,
+    },
+    Unary { op: UnaryOp, operand: Box<Expr> },
+    Binary { op: BinaryOp, lhs: Box<Expr>, rhs: Box<Expr> },
+}
+
+impl Expr {
+    /// Evaluates in `context`'s numeric mode, without the final rounding
+    /// of `Context::finish`.
+    pub fn eval(&self, context: &Context) -> Result<Value, numeric::Error> {
+        match self {
+            Expr::Number { literal, .. } => context.number(literal),
+            Expr::Unary { op: UnaryOp::Neg, operand } => Ok(context.negate(&operand.eval(context)?)),
+            Expr::Unary { op: UnaryOp::Plus, operand } => operand.eval(context),
+            Expr::Binary { op, lhs, rhs } => context.apply(*op, &lhs.eval(context)?, &rhs.eval(context)?),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub position: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    fn new(position: usize, message: impl Into<String>) -> ParseError {
+        ParseError { position, message: message.into() }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token {
+    /// The value and the end of its text.
+    Number(f64, usize),
+    Op(char),
+    LParen,
+    RParen,
+}
+
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+
+    while let Some(&(pos, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut end = pos;
+            while let Some(&(i, d)) = chars.peek() {
+                if d.is_ascii_digit() || d == '.' {
+                    end = i + d.len_utf8();
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            // Optional exponent part: 1e10, 2.5E-3
+            if let Some(&(i, e)) = chars.peek() {
+                if e == 'e' || e == 'E' {
+                    let rest = &input[i + 1..];
+                    let sign_len = usize::from(rest.starts_with(['+', '-']));
+                    let digits = rest[sign_len..].chars().take_while(|d| d.is_ascii_digit()).count();
+                    if digits > 0 {
+                        end = i + 1 + sign_len + digits;
+                        while chars.peek().is_some_and(|&(j, _)| j < end) {
+                            chars.next();
+                        }
+                    }
+                }
+            }
+            let text = &input[pos..end];
+            let value = text
+                .parse::<f64>()
+                .map_err(|_| ParseError::new(pos, format!("invalid number '{text}'")))?;
+            tokens.push((pos, Token::Number(value, end)));
+        } else {
+            let token = match c {
+                '(' => Token::LParen,
+                ')' => Token::RParen,
+                '+' | '-' | '*' | '/' | '^' => Token::Op(c),
+                _ => return Err(ParseError::new(pos, format!("unexpected character '{c}'"))),
+            };
+            tokens.push((pos, token));
+            chars.next();
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    end: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<Token> {
+        self.tokens.get(self.pos).map(|&(_, t)| t)
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens.get(self.pos).map_or(self.end, |&(p, _)| p)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek();
+        self.pos += 1;
+        token
+    }
+
+    /// Parses an expression starting `depth` levels below the root, with
+    /// the height of its tree.
+    fn expression(&mut self, min_precedence: u8, depth: usize) -> Result<(Expr, usize), ParseError> {
+        let (mut lhs, mut height) = self.prefix(depth)?;
+
+        while let Some(Token::Op(symbol)) = self.peek() {
+            let op = BinaryOp::from_symbol(symbol).unwrap();
+            if op.precedence() < min_precedence {
+                break;
+            }
+            let offset = self.offset();
+            self.next();
+            let next_min = if op.is_right_assoc() { op.precedence() } else { op.precedence() + 1 };
+            let (rhs, rhs_height) = self.expression(next_min, depth + 1)?;
+            height = height.max(rhs_height) + 1;
+            if depth + height > MAX_NESTING {
+                return Err(too_deep(offset));
+            }
+            lhs = Expr::Binary { op, lhs: Box::new(lhs), rhs: Box::new(rhs) };
+        }
+
+        Ok((lhs, height))
+    }
+
+    fn prefix(&mut self, depth: usize) -> Result<(Expr, usize), ParseError> {
+        let offset = self.offset();
+        if depth >= MAX_NESTING {
+            return Err(too_deep(offset));
+        }
+        match self.next() {
+            Some(Token::Number(value, end)) => Ok((Expr::Number { value, literal: self.input[offset..end].to_string() }, 1)),
+            Some(Token::Op(symbol @ ('-' | '+'))) => {
+                let op = if symbol == '-' { UnaryOp::Neg } else { UnaryOp::Plus };
+                let (operand, height) = self.expression(UNARY_PRECEDENCE, depth + 1)?;
+                Ok((Expr::Unary { op, operand: Box::new(operand) }, height + 1))
+            }
+            Some(Token::LParen) => {
+                let (inner, height) = self.expression(0, depth + 1)?;
+                match self.next() {
+                    Some(Token::RParen) => Ok((inner, height + 1)),
+                    _ => Err(ParseError::new(offset, "unclosed parenthesis")),
+                }
+            }
+            Some(Token::Op(symbol)) => Err(ParseError::new(offset, format!("unexpected operator '{symbol}'"))),
+            Some(Token::RParen) => Err(ParseError::new(offset, "unexpected ')'")),
+            None => Err(ParseError::new(offset, "unexpected end of expression")),
+        }
+    }
+}
+
+fn too_deep(position: usize) -> ParseError {
+    ParseError::new(position, format!("expression nested too deeply (at most {MAX_NESTING} levels)"))
+}
+
+pub fn parse(input: &str) -> Result<Expr, ParseError> {
+    let tokens = tokenize(input)?;
+    let mut parser = Parser { input, tokens, pos: 0, end: input.len() };
+    let (expr, _) = parser.expression(0, 0)?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(Token::RParen) => Err(ParseError::new(parser.offset(), "unmatched ')'")),
+        Some(_) => Err(ParseError::new(parser.offset(), "expected operator")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::numeric::{JsonNumber, Limits, Mode};
+
+    fn eval(input: &str, mode: Mode) -> JsonNumber {
+        let context = Limits::default().context(mode, None, false).unwrap();
+        let value = parse(input).unwrap().eval(&context).unwrap();
+        context.render(&context.finish(value)).0
+    }
+
+    #[test]
+    fn follows_precedence_and_associativity() {
+        assert_eq!(eval("(2 + 3) * -4 ^ 2", Mode::F64), JsonNumber::Number(-80.0));
+        assert_eq!(eval("-2^2", Mode::F64), JsonNumber::Number(-4.0));
+        assert_eq!(eval("2^3^2", Mode::F64), JsonNumber::Number(512.0));
+        assert_eq!(eval("10 - 4 - 3", Mode::F64), JsonNumber::Number(3.0));
+    }
+
+    #[test]
+    fn exact_modes_read_literals_as_written() {
+        assert_eq!(eval("0.1 + 0.2", Mode::F64), JsonNumber::Number(0.30000000000000004));
+        assert_eq!(eval("0.1 + 0.2", Mode::Decimal), JsonNumber::Number(0.3));
+        assert_eq!(eval("1/3 + 1/6", Mode::Rational), JsonNumber::Text("1/2".to_string()));
+    }
+
+    #[test]
+    fn reports_error_positions() {
+        assert_eq!(parse("1 +").unwrap_err().position, 3);
+        assert_eq!(parse("(1").unwrap_err().position, 0);
+        assert_eq!(parse("1 $ 2").unwrap_err().position, 2);
+    }
+
+    #[test]
+    fn rejects_deep_nesting() {
+        let too_deep = |input: String| parse(&input).unwrap_err().message.starts_with("expression nested too deeply");
+        assert!(too_deep(format!("{}1", "-".repeat(10_000))));
+        assert!(too_deep(format!("{}1{}", "(".repeat(10_000), ")".repeat(10_000))));
+        assert!(too_deep(format!("{}1", "1 + ".repeat(10_000))));
+        assert!(too_deep(format!("{}1", "2 ^ ".repeat(10_000))));
+
+        let nested = format!("{}1{}", "(-".repeat(100), ")".repeat(100));
+        assert_eq!(eval(&nested, Mode::F64), JsonNumber::Number(1.0));
+        assert_eq!(eval(&format!("{}1", "1 + ".repeat(200)), Mode::F64), JsonNumber::Number(201.0));
+    }
+}
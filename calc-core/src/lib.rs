@@ -0,0 +1,11 @@
+//! The calculator shared by lab1 and lab2: operators, the expression
+//! engine, numeric modes and their errors. The servers own their HTTP wire
+//! formats and storage; everything that decides what a calculation returns
+//! lives here so both answer the same way.
+
+pub mod expr;
+pub mod numeric;
+pub mod operators;
+
+#[cfg(test)]
+mod properties;
@@ -0,0 +1,483 @@
+//! Numeric modes: how a calculation computes and how its result is sent.
+//!
+//! | mode            | arithmetic                                   | `result`                                  |
+//! |-----------------|----------------------------------------------|-------------------------------------------|
+//! | `f64` (default) | IEEE 754 doubles                             | JSON number                               |
+//! | `decimal`       | exact, but `/`, negative `^` and the final result are rounded to `scale` digits after the point, half to even | JSON number if it survives a round trip through a double, otherwise a string such as `"12345678901234567890.5"` |
+//! | `rational`      | exact fractions                              | JSON number for integers that fit, otherwise a string such as `"1/3"` |
+//!
+//! Operands may be JSON numbers or strings; send strings for values a
+//! double cannot hold. The exact modes only take integer exponents and
+//! refuse numbers over `MAX_BITS` bits, so a single request cannot make
+//! the server build arbitrarily large integers.
+//!
+//! Division by zero, a result too large for the mode and, in `f64` mode, a
+//! NaN are errors (`Error::is_arithmetic`). A request may set `ieee` to get
+//! IEEE 754 results in `f64` mode instead: `inf`, `-inf` and `NaN`, which are
+//! sent, stored and accepted back as operands as those strings, since JSON
+//! numbers cannot express them. The exact modes have no such values and
+//! ignore `ieee`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_rational::BigRational;
+use num_traits::{One, Signed, ToPrimitive, Zero};
+use serde::Serializer;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::expr::BinaryOp;
+
+/// Largest numerator or denominator, in bits, of a number in an exact mode.
+pub const MAX_BITS: u64 = 1 << 16;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    #[default]
+    F64,
+    Decimal,
+    Rational,
+}
+
+impl Mode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Mode::F64 => "f64",
+            Mode::Decimal => "decimal",
+            Mode::Rational => "rational",
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Mode, String> {
+        match s {
+            "f64" => Ok(Mode::F64),
+            "decimal" => Ok(Mode::Decimal),
+            "rational" => Ok(Mode::Rational),
+            _ => Err(format!("unknown numeric mode '{s}'")),
+        }
+    }
+}
+
+/// A number as a client sent it.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Operand {
+    Number(f64),
+    Text(String),
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Number(value) => write!(f, "{value}"),
+            Operand::Text(text) => write!(f, "{text}"),
+        }
+    }
+}
+
+/// A result as it is sent back.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum JsonNumber {
+    Number(f64),
+    Text(String),
+}
+
+/// A JSON number when `text` reads back unchanged from a double,
+/// otherwise `text` itself.
+pub fn json_number(text: &str) -> JsonNumber {
+    match text.parse::<f64>() {
+        Ok(value) if value.is_finite() && value.to_string() == text => JsonNumber::Number(value),
+        _ => JsonNumber::Text(text.to_string()),
+    }
+}
+
+impl From<f64> for JsonNumber {
+    /// Non-finite values become `"inf"`, `"-inf"` and `"NaN"`.
+    fn from(value: f64) -> JsonNumber {
+        if value.is_finite() {
+            JsonNumber::Number(value)
+        } else {
+            JsonNumber::Text(value.to_string())
+        }
+    }
+}
+
+/// `serialize_with` for doubles that may be non-finite, which serde_json
+/// would otherwise turn into `null`.
+pub fn serialize_f64<S: Serializer>(value: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+    serde::Serialize::serialize(&JsonNumber::from(*value), serializer)
+}
+
+pub fn serialize_option_f64<S: Serializer>(value: &Option<f64>, serializer: S) -> Result<S::Ok, S::Error> {
+    serde::Serialize::serialize(&value.map(JsonNumber::from), serializer)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Float(f64),
+    Exact(BigRational),
+}
+
+impl Value {
+    /// The nearest double, for sorting and filtering history.
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            Value::Float(value) => *value,
+            Value::Exact(value) => value.to_f64().unwrap_or(f64::NAN),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    InvalidNumber(String),
+    DivisionByZero,
+    FractionalExponent,
+    TooLarge,
+    ScaleTooLarge(u32),
+    /// An `f64` result out of range.
+    Overflow,
+    NotANumber,
+}
+
+impl Error {
+    /// Whether the request was well-formed but its arithmetic has no result.
+    pub fn is_arithmetic(&self) -> bool {
+        matches!(self, Error::DivisionByZero | Error::TooLarge | Error::Overflow | Error::NotANumber)
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::InvalidNumber(_) | Error::FractionalExponent | Error::ScaleTooLarge(_) => "bad_request",
+            Error::DivisionByZero => "division_by_zero",
+            Error::TooLarge | Error::Overflow => "overflow",
+            Error::NotANumber => "not_a_number",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidNumber(text) => write!(f, "invalid number '{text}'"),
+            Error::DivisionByZero => write!(f, "division by zero"),
+            Error::FractionalExponent => write!(f, "exponents must be integers in exact modes"),
+            Error::TooLarge => write!(f, "number too large for an exact mode"),
+            Error::ScaleTooLarge(max) => write!(f, "scale must be at most {max}"),
+            Error::Overflow => write!(f, "result out of range"),
+            Error::NotANumber => write!(f, "result is not a number"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Server-wide settings from the `[numeric]` config section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub default_scale: u32,
+    pub max_scale: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits { default_scale: 20, max_scale: 100 }
+    }
+}
+
+impl Limits {
+    pub fn context(&self, mode: Mode, scale: Option<u32>, ieee: bool) -> Result<Context, Error> {
+        let scale = scale.unwrap_or(self.default_scale);
+        if scale > self.max_scale {
+            return Err(Error::ScaleTooLarge(self.max_scale));
+        }
+        Ok(Context { mode, scale, ieee })
+    }
+}
+
+/// How one request computes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Context {
+    pub mode: Mode,
+    /// Digits after the decimal point in `decimal` mode.
+    pub scale: u32,
+    /// Lets `f64` mode produce and accept `inf`, `-inf` and `NaN`.
+    pub ieee: bool,
+}
+
+impl Context {
+    /// Parses a number literal such as `-12.5e3`, or in `ieee` mode also
+    /// `inf`, `-inf` and `NaN`.
+    pub fn number(&self, text: &str) -> Result<Value, Error> {
+        let invalid = || Error::InvalidNumber(text.to_string());
+        let literal = Literal::split(text);
+        match self.mode {
+            Mode::F64 => {
+                let value: f64 = text.parse().map_err(|_| invalid())?;
+                if literal.is_none() && !self.ieee {
+                    return Err(invalid());
+                }
+                self.checked_float(value).map(Value::Float)
+            },
+            Mode::Decimal | Mode::Rational => literal.ok_or_else(invalid)?.exact().map(Value::Exact),
+        }
+    }
+
+    pub fn operand(&self, operand: &Operand) -> Result<Value, Error> {
+        match (operand, self.mode) {
+            (Operand::Number(value), Mode::F64) => Ok(Value::Float(*value)),
+            // The shortest text that reads back as the same double, so
+            // 0.1 means one tenth rather than the double nearest to it.
+            (Operand::Number(value), _) => self.number(&value.to_string()),
+            (Operand::Text(text), _) => self.number(text),
+        }
+    }
+
+    pub fn apply(&self, op: BinaryOp, lhs: &Value, rhs: &Value) -> Result<Value, Error> {
+        let (lhs, rhs) = match (lhs, rhs) {
+            (Value::Exact(lhs), Value::Exact(rhs)) => (lhs, rhs),
+            _ => {
+                let (lhs, rhs) = (lhs.to_f64(), rhs.to_f64());
+                let divides_by_zero = match op {
+                    BinaryOp::Div => rhs == 0.0,
+                    BinaryOp::Pow => lhs == 0.0 && rhs < 0.0,
+                    _ => false,
+                };
+                if divides_by_zero && !self.ieee {
+                    return Err(Error::DivisionByZero);
+                }
+                return self.checked_float(op.apply(lhs, rhs)).map(Value::Float);
+            },
+        };
+        let result = match op {
+            BinaryOp::Add => lhs + rhs,
+            BinaryOp::Sub => lhs - rhs,
+            BinaryOp::Mul => lhs * rhs,
+            BinaryOp::Div => {
+                if rhs.is_zero() {
+                    return Err(Error::DivisionByZero);
+                }
+                self.round(lhs / rhs)
+            },
+            BinaryOp::Pow => self.pow(lhs, rhs)?,
+        };
+        checked(result).map(Value::Exact)
+    }
+
+    pub fn negate(&self, value: &Value) -> Value {
+        match value {
+            Value::Float(value) => Value::Float(-value),
+            Value::Exact(value) => Value::Exact(-value),
+        }
+    }
+
+    /// Rounds the result of a whole calculation; only `decimal` mode rounds.
+    pub fn finish(&self, value: Value) -> Value {
+        match value {
+            Value::Exact(value) => Value::Exact(self.round(value)),
+            value => value,
+        }
+    }
+
+    /// The result to send back, and its exact text to keep in the history,
+    /// which `f64` results do not have.
+    pub fn render(&self, value: &Value) -> (JsonNumber, Option<String>) {
+        match value {
+            Value::Float(value) => (JsonNumber::from(*value), None),
+            Value::Exact(value) => {
+                let text = match self.mode {
+                    Mode::Decimal => decimal_text(value, self.scale),
+                    _ if value.is_integer() => value.numer().to_string(),
+                    _ => format!("{}/{}", value.numer(), value.denom()),
+                };
+                (json_number(&text), Some(text))
+            },
+        }
+    }
+
+    fn checked_float(&self, value: f64) -> Result<f64, Error> {
+        match value {
+            _ if self.ieee || value.is_finite() => Ok(value),
+            _ if value.is_nan() => Err(Error::NotANumber),
+            _ => Err(Error::Overflow),
+        }
+    }
+
+    fn round(&self, value: BigRational) -> BigRational {
+        match self.mode {
+            Mode::Decimal => round_half_even(&value, self.scale),
+            _ => value,
+        }
+    }
+
+    fn pow(&self, base: &BigRational, exponent: &BigRational) -> Result<BigRational, Error> {
+        if !exponent.is_integer() {
+            return Err(Error::FractionalExponent);
+        }
+        let exponent = exponent.to_integer();
+        // 0, 1 and -1 stay small whatever the exponent.
+        let bits = if base.is_zero() || base.abs().is_one() { 0 } else { base.numer().bits().max(base.denom().bits()) };
+        let n = exponent.magnitude().to_u64()
+            .filter(|n| bits.saturating_mul(*n) <= MAX_BITS && *n <= u64::from(u32::MAX))
+            .ok_or(Error::TooLarge)?;
+        let power = num_traits::pow(base.clone(), n as usize);
+        if exponent.is_negative() {
+            if power.is_zero() {
+                return Err(Error::DivisionByZero);
+            }
+            return Ok(self.round(power.recip()));
+        }
+        Ok(power)
+    }
+}
+
+fn checked(value: BigRational) -> Result<BigRational, Error> {
+    if value.numer().bits() > MAX_BITS || value.denom().bits() > MAX_BITS {
+        return Err(Error::TooLarge);
+    }
+    Ok(value)
+}
+
+fn ten_to(exponent: u64) -> BigInt {
+    num_traits::pow(BigInt::from(10), exponent as usize)
+}
+
+fn round_half_even(value: &BigRational, scale: u32) -> BigRational {
+    let factor = ten_to(u64::from(scale));
+    let scaled = value * &factor;
+    let floor = scaled.floor();
+    let fraction = &scaled - &floor;
+    let half = BigRational::new(BigInt::one(), BigInt::from(2));
+    let mut rounded = floor.to_integer();
+    if fraction > half || (fraction == half && rounded.is_odd()) {
+        rounded += 1;
+    }
+    BigRational::new(rounded, factor)
+}
+
+/// `value`, already rounded to `scale` digits, without trailing zeros.
+fn decimal_text(value: &BigRational, scale: u32) -> String {
+    let digits = (value * ten_to(u64::from(scale))).to_integer();
+    let sign = if digits.is_negative() { "-" } else { "" };
+    let digits = format!("{:0>width$}", digits.magnitude(), width = scale as usize + 1);
+    let (int, frac) = digits.split_at(digits.len() - scale as usize);
+    let frac = frac.trim_end_matches('0');
+    if frac.is_empty() {
+        format!("{sign}{int}")
+    } else {
+        format!("{sign}{int}.{frac}")
+    }
+}
+
+/// The parts of `[+-]digits[.digits][e[+-]digits]`.
+struct Literal<'a> {
+    negative: bool,
+    int: &'a str,
+    frac: &'a str,
+    exponent: i64,
+}
+
+impl<'a> Literal<'a> {
+    fn split(text: &'a str) -> Option<Literal<'a>> {
+        let (negative, rest) = match text.as_bytes().first()? {
+            b'-' => (true, &text[1..]),
+            b'+' => (false, &text[1..]),
+            _ => (false, text),
+        };
+        let (mantissa, exponent) = match rest.split_once(['e', 'E']) {
+            Some((mantissa, exponent)) => (mantissa, exponent.parse().ok()?),
+            None => (rest, 0),
+        };
+        let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        let is_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+        if (int.is_empty() && frac.is_empty()) || !is_digits(int) || !is_digits(frac) {
+            return None;
+        }
+        Some(Literal { negative, int, frac, exponent })
+    }
+
+    fn exact(&self) -> Result<BigRational, Error> {
+        let exponent = self.exponent.checked_sub(self.frac.len() as i64).ok_or(Error::TooLarge)?;
+        if exponent.unsigned_abs() > MAX_BITS {
+            return Err(Error::TooLarge);
+        }
+        let digits: BigInt = format!("{}{}", self.int, self.frac).parse().map_err(|_| Error::TooLarge)?;
+        let power = ten_to(exponent.unsigned_abs());
+        let value = if exponent >= 0 {
+            BigRational::from_integer(digits * power)
+        } else {
+            BigRational::new(digits, power)
+        };
+        checked(if self.negative { -value } else { value })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(mode: Mode) -> Context {
+        Limits::default().context(mode, Some(5), false).unwrap()
+    }
+
+    fn calculate(context: Context, lhs: &str, op: BinaryOp, rhs: &str) -> Result<JsonNumber, Error> {
+        let value = context.apply(op, &context.number(lhs)?, &context.number(rhs)?)?;
+        Ok(context.render(&context.finish(value)).0)
+    }
+
+    #[test]
+    fn decimal_rounds_half_to_even() {
+        let decimal = context(Mode::Decimal);
+        assert_eq!(calculate(decimal, "0.1", BinaryOp::Add, "0.2"), Ok(JsonNumber::Number(0.3)));
+        assert_eq!(calculate(decimal, "2", BinaryOp::Div, "3"), Ok(JsonNumber::Number(0.66667)));
+        assert_eq!(calculate(decimal, "0.000025", BinaryOp::Mul, "1"), Ok(JsonNumber::Number(0.00002)));
+        assert_eq!(calculate(decimal, "-0.000035", BinaryOp::Mul, "1"), Ok(JsonNumber::Number(-0.00004)));
+        assert_eq!(calculate(decimal, "1e20", BinaryOp::Add, "0.5"), Ok(JsonNumber::Text("100000000000000000000.5".to_string())));
+    }
+
+    #[test]
+    fn rational_is_exact() {
+        let rational = context(Mode::Rational);
+        assert_eq!(calculate(rational, "1", BinaryOp::Div, "3"), Ok(JsonNumber::Text("1/3".to_string())));
+        assert_eq!(calculate(rational, "2", BinaryOp::Pow, "-2"), Ok(JsonNumber::Text("1/4".to_string())));
+        assert_eq!(calculate(rational, "1.5", BinaryOp::Mul, "4"), Ok(JsonNumber::Number(6.0)));
+        assert_eq!(calculate(rational, "2", BinaryOp::Pow, "0.5"), Err(Error::FractionalExponent));
+        assert_eq!(calculate(rational, "0", BinaryOp::Pow, "-1"), Err(Error::DivisionByZero));
+        assert_eq!(calculate(rational, "3", BinaryOp::Pow, "100000"), Err(Error::TooLarge));
+        assert_eq!(calculate(rational, "1", BinaryOp::Pow, "4000000000"), Ok(JsonNumber::Number(1.0)));
+    }
+
+    #[test]
+    fn non_finite_results_need_ieee() {
+        let f64 = context(Mode::F64);
+        assert_eq!(calculate(f64, "1", BinaryOp::Div, "0"), Err(Error::DivisionByZero));
+        assert_eq!(calculate(f64, "1e308", BinaryOp::Mul, "10"), Err(Error::Overflow));
+        assert_eq!(f64.number("inf"), Err(Error::InvalidNumber("inf".to_string())));
+
+        let ieee = Context { ieee: true, ..f64 };
+        assert_eq!(calculate(ieee, "-1", BinaryOp::Div, "0"), Ok(JsonNumber::Text("-inf".to_string())));
+        assert_eq!(calculate(ieee, "inf", BinaryOp::Sub, "inf"), Ok(JsonNumber::Text("NaN".to_string())));
+    }
+
+    #[test]
+    fn scale_is_limited() {
+        let limits = Limits { default_scale: 2, max_scale: 4 };
+        assert_eq!(limits.context(Mode::Decimal, None, false).map(|context| context.scale), Ok(2));
+        assert_eq!(limits.context(Mode::Decimal, Some(5), false), Err(Error::ScaleTooLarge(4)));
+    }
+
+    #[test]
+    fn json_numbers_only_when_lossless() {
+        assert_eq!(json_number("0.25"), JsonNumber::Number(0.25));
+        assert_eq!(json_number("12345678901234567891"), JsonNumber::Text("12345678901234567891".to_string()));
+        assert_eq!(json_number("1/3"), JsonNumber::Text("1/3".to_string()));
+    }
+}
@@ -0,0 +1,115 @@
+//! Operators as the servers list and store them.
+//!
+//! Each operator names its implementation by `symbol`, which must be a
+//! binary operator of the expression engine with the same precedence, so a
+//! dropdown, the stored expression text and the expression engine never
+//! disagree.
+
+use std::collections::BTreeMap;
+
+use serde_derive::Serialize;
+
+use crate::expr::BinaryOp;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Operator {
+    pub id: i32,
+    pub name: String,
+    pub symbol: char,
+    pub arity: u8,
+    pub precedence: u8,
+    #[serde(skip)]
+    pub op: BinaryOp,
+}
+
+impl Operator {
+    /// Checks a stored operator against the expression engine; an absent
+    /// `name` defaults to the symbol.
+    pub fn new(id: i32, name: Option<String>, symbol: &str, arity: i64, precedence: i64) -> Result<Operator, String> {
+        let mut chars = symbol.chars();
+        let op = match (chars.next(), chars.next()) {
+            (Some(c), None) => BinaryOp::from_symbol(c),
+            _ => None,
+        }
+        .ok_or_else(|| format!("no implementation for symbol '{symbol}'"))?;
+        if arity != 2 {
+            return Err(format!("arity {arity} is not supported, only binary operators are"));
+        }
+        if precedence != i64::from(op.precedence()) {
+            return Err(format!(
+                "precedence {precedence} does not match {} used by the expression engine for '{}'",
+                op.precedence(),
+                op.symbol()
+            ));
+        }
+
+        Ok(Operator {
+            id,
+            name: name.unwrap_or_else(|| symbol.to_string()),
+            symbol: op.symbol(),
+            arity: 2,
+            precedence: op.precedence(),
+            op,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Registry {
+    operators: BTreeMap<i32, Operator>,
+}
+
+impl Registry {
+    /// The operators lab2's migrations seed, by the same ids.
+    pub fn builtin() -> Registry {
+        let ops = [(1, "Addition", BinaryOp::Add), (2, "Subtraction", BinaryOp::Sub), (3, "Multiplication", BinaryOp::Mul), (4, "Division", BinaryOp::Div), (5, "Exponentiation", BinaryOp::Pow)];
+        ops.into_iter().map(|(id, name, op)| Operator {
+            id,
+            name: name.to_string(),
+            symbol: op.symbol(),
+            arity: 2,
+            precedence: op.precedence(),
+            op,
+        }).collect()
+    }
+
+    pub fn get(&self, id: i32) -> Option<&Operator> {
+        self.operators.get(&id)
+    }
+
+    pub fn by_symbol(&self, symbol: char) -> Option<&Operator> {
+        self.operators.values().find(|operator| operator.symbol == symbol)
+    }
+
+    pub fn list(&self) -> impl Iterator<Item = &Operator> {
+        self.operators.values()
+    }
+}
+
+impl FromIterator<Operator> for Registry {
+    fn from_iter<I: IntoIterator<Item = Operator>>(operators: I) -> Registry {
+        Registry { operators: operators.into_iter().map(|operator| (operator.id, operator)).collect() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_operators_must_match_the_engine() {
+        let pow = Operator::new(7, None, "^", 2, 4).unwrap();
+        assert_eq!((pow.name.as_str(), pow.op), ("^", BinaryOp::Pow));
+        assert!(Operator::new(7, None, "%", 2, 2).is_err());
+        assert!(Operator::new(7, None, "+", 1, 1).is_err());
+        assert!(Operator::new(7, None, "+", 2, 2).is_err());
+    }
+
+    #[test]
+    fn builtin_covers_every_binary_operator() {
+        let registry = Registry::builtin();
+        assert_eq!(registry.list().count(), 5);
+        assert_eq!(registry.by_symbol('/').map(|operator| operator.id), Some(4));
+        assert!(registry.by_symbol('%').is_none());
+    }
+}
@@ -0,0 +1,170 @@
+//! Property tests of the arithmetic: algebraic laws in the exact modes,
+//! rounding in `decimal` mode, `f64` mode against exact results, and the
+//! expression engine against single operations.
+
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{One, Signed, ToPrimitive, Zero};
+use proptest::prelude::*;
+
+use crate::expr::{self, BinaryOp};
+use crate::numeric::{Context, Error, Limits, Mode, Value};
+
+fn context(mode: Mode, scale: u32) -> Context {
+    Limits::default().context(mode, Some(scale), false).unwrap()
+}
+
+fn exact(value: &Value) -> BigRational {
+    match value {
+        Value::Exact(value) => value.clone(),
+        Value::Float(value) => panic!("expected an exact value, got {value}"),
+    }
+}
+
+fn float(value: &Value) -> f64 {
+    match value {
+        Value::Float(value) => *value,
+        Value::Exact(value) => panic!("expected a float, got {value}"),
+    }
+}
+
+/// Decimal literals such as `-1234.0567`, with up to four digits after the point.
+fn literal() -> impl Strategy<Value = String> {
+    (-1_000_000i64..1_000_000, 0u32..10_000, 0usize..=4).prop_map(|(int, frac, digits)| {
+        let frac = format!("{frac:04}");
+        match digits {
+            0 => int.to_string(),
+            _ => format!("{int}.{}", &frac[..digits]),
+        }
+    })
+}
+
+fn nonzero_literal() -> impl Strategy<Value = String> {
+    literal().prop_filter("nonzero", |text| text.parse::<f64>().unwrap() != 0.0)
+}
+
+fn op() -> impl Strategy<Value = BinaryOp> {
+    proptest::sample::select(BinaryOp::ALL.to_vec())
+}
+
+proptest! {
+    #[test]
+    fn rational_addition_and_multiplication_commute(a in literal(), b in literal()) {
+        let rational = context(Mode::Rational, 0);
+        let (a, b) = (rational.number(&a).unwrap(), rational.number(&b).unwrap());
+        for op in [BinaryOp::Add, BinaryOp::Mul] {
+            prop_assert_eq!(rational.apply(op, &a, &b), rational.apply(op, &b, &a));
+        }
+    }
+
+    #[test]
+    fn rational_identities_hold(a in literal()) {
+        let rational = context(Mode::Rational, 0);
+        let a = rational.number(&a).unwrap();
+        let (zero, one) = (Value::Exact(BigRational::zero()), Value::Exact(BigRational::one()));
+        prop_assert_eq!(rational.apply(BinaryOp::Add, &a, &zero).unwrap(), a.clone());
+        prop_assert_eq!(rational.apply(BinaryOp::Sub, &a, &zero).unwrap(), a.clone());
+        prop_assert_eq!(rational.apply(BinaryOp::Mul, &a, &one).unwrap(), a.clone());
+        prop_assert_eq!(rational.apply(BinaryOp::Div, &a, &one).unwrap(), a.clone());
+        prop_assert_eq!(rational.apply(BinaryOp::Pow, &a, &one).unwrap(), a.clone());
+        prop_assert_eq!(rational.apply(BinaryOp::Pow, &a, &zero).unwrap(), one);
+        prop_assert_eq!(rational.apply(BinaryOp::Sub, &a, &a).unwrap(), zero);
+    }
+
+    #[test]
+    fn rational_operations_have_inverses(a in literal(), b in nonzero_literal(), n in -4i64..=4) {
+        let rational = context(Mode::Rational, 0);
+        let (a, b) = (rational.number(&a).unwrap(), rational.number(&b).unwrap());
+        let sum = rational.apply(BinaryOp::Add, &a, &b).unwrap();
+        prop_assert_eq!(rational.apply(BinaryOp::Sub, &sum, &b).unwrap(), a.clone());
+        let product = rational.apply(BinaryOp::Mul, &a, &b).unwrap();
+        prop_assert_eq!(rational.apply(BinaryOp::Div, &product, &b).unwrap(), a.clone());
+        let quotient = rational.apply(BinaryOp::Div, &a, &b).unwrap();
+        prop_assert_eq!(rational.apply(BinaryOp::Mul, &quotient, &b).unwrap(), a);
+
+        let n = Value::Exact(BigRational::from_integer(BigInt::from(n)));
+        let power = rational.apply(BinaryOp::Pow, &b, &n).unwrap();
+        let inverse = rational.apply(BinaryOp::Pow, &b, &rational.negate(&n)).unwrap();
+        prop_assert_eq!(rational.apply(BinaryOp::Mul, &power, &inverse).unwrap(), Value::Exact(BigRational::one()));
+    }
+
+    #[test]
+    fn decimal_rounds_to_the_nearest_even_digit(a in literal(), b in nonzero_literal(), op in op(), scale in 0u32..=8) {
+        prop_assume!(op != BinaryOp::Pow);
+        let (decimal, rational) = (context(Mode::Decimal, scale), context(Mode::Rational, scale));
+        let rounded = exact(&decimal.finish(decimal.apply(op, &decimal.number(&a).unwrap(), &decimal.number(&b).unwrap()).unwrap()));
+        let exact = exact(&rational.apply(op, &rational.number(&a).unwrap(), &rational.number(&b).unwrap()).unwrap());
+
+        let unit = BigRational::new(BigInt::one(), num_traits::pow(BigInt::from(10), scale as usize));
+        let steps = &rounded / &unit;
+        prop_assert!(steps.is_integer(), "{} has more than {} digits after the point", rounded, scale);
+        let error = (&rounded - &exact).abs() / &unit;
+        let half = BigRational::new(BigInt::one(), BigInt::from(2));
+        prop_assert!(error <= half, "{} is not the nearest step to {}", rounded, exact);
+        if error == half {
+            prop_assert!(steps.to_integer() % 2 == BigInt::zero(), "{} broke a tie away from even", rounded);
+        }
+    }
+
+    #[test]
+    fn decimal_is_exact_within_its_scale(a in literal(), b in literal(), op in prop_oneof![Just(BinaryOp::Add), Just(BinaryOp::Sub)]) {
+        let (decimal, rational) = (context(Mode::Decimal, 4), context(Mode::Rational, 4));
+        let rounded = decimal.apply(op, &decimal.number(&a).unwrap(), &decimal.number(&b).unwrap()).unwrap();
+        let exact = rational.apply(op, &rational.number(&a).unwrap(), &rational.number(&b).unwrap()).unwrap();
+        prop_assert_eq!(decimal.finish(rounded), exact);
+    }
+
+    #[test]
+    fn f64_laws_hold(a in any::<f64>(), b in any::<f64>()) {
+        prop_assume!(a.is_finite() && b.is_finite());
+        let f64 = context(Mode::F64, 0);
+        let (a, b) = (Value::Float(a), Value::Float(b));
+        for op in [BinaryOp::Add, BinaryOp::Mul] {
+            prop_assert_eq!(f64.apply(op, &a, &b), f64.apply(op, &b, &a));
+        }
+        prop_assert_eq!(f64.apply(BinaryOp::Add, &a, &Value::Float(0.0)).unwrap(), a.clone());
+        prop_assert_eq!(f64.apply(BinaryOp::Mul, &a, &Value::Float(1.0)).unwrap(), a.clone());
+        prop_assert_eq!(f64.apply(BinaryOp::Sub, &a, &a).unwrap(), Value::Float(0.0));
+        if float(&a) != 0.0 {
+            prop_assert_eq!(f64.apply(BinaryOp::Div, &a, &a).unwrap(), Value::Float(1.0));
+        }
+    }
+
+    #[test]
+    fn f64_results_are_finite_or_errors(a in any::<f64>(), b in any::<f64>(), op in op()) {
+        prop_assume!(a.is_finite() && b.is_finite());
+        let f64 = context(Mode::F64, 0);
+        match f64.apply(op, &Value::Float(a), &Value::Float(b)) {
+            Ok(value) => prop_assert!(float(&value).is_finite()),
+            Err(err) => prop_assert!(err.is_arithmetic(), "{:?} {} {:?} failed with {:?}", a, op.symbol(), b, err),
+        }
+    }
+
+    /// Reading each operand and the operation itself round once each, so
+    /// `f64` stays within a few ulps of the exact result: of the result for
+    /// `*` and `/`, of the operands for `+` and `-`, which can cancel.
+    #[test]
+    fn f64_agrees_with_rational(a in literal(), b in nonzero_literal(), op in prop_oneof![Just(BinaryOp::Add), Just(BinaryOp::Sub), Just(BinaryOp::Mul), Just(BinaryOp::Div)]) {
+        let (f64, rational) = (context(Mode::F64, 0), context(Mode::Rational, 0));
+        let float = float(&f64.apply(op, &f64.number(&a).unwrap(), &f64.number(&b).unwrap()).unwrap());
+        let exact = exact(&rational.apply(op, &rational.number(&a).unwrap(), &rational.number(&b).unwrap()).unwrap()).to_f64().unwrap();
+        let magnitude = match op {
+            BinaryOp::Add | BinaryOp::Sub => a.parse::<f64>().unwrap().abs() + b.parse::<f64>().unwrap().abs(),
+            _ => exact.abs(),
+        };
+        let tolerance = 4.0 * f64::EPSILON * magnitude.max(f64::MIN_POSITIVE);
+        prop_assert!((float - exact).abs() <= tolerance, "{} {} {}: f64 gave {}, exact {}", a, op.symbol(), b, float, exact);
+    }
+
+    #[test]
+    fn expressions_agree_with_single_operations(a in literal(), b in literal(), op in op(), mode in prop_oneof![Just(Mode::F64), Just(Mode::Decimal), Just(Mode::Rational)]) {
+        prop_assume!(op != BinaryOp::Pow || b.parse::<f64>().unwrap().abs() <= 4.0);
+        let context = context(mode, 6);
+        let applied = context.apply(op, &context.number(&a).unwrap(), &context.number(&b).unwrap()).map(|value| context.finish(value));
+        let evaluated = expr::parse(&format!("({a}) {} ({b})", op.symbol())).unwrap().eval(&context).map(|value| context.finish(value));
+        prop_assert_eq!(applied.clone(), evaluated);
+        if let Err(err) = applied {
+            prop_assert!(err.is_arithmetic() || err == Error::FractionalExponent, "unexpected {:?}", err);
+        }
+    }
+}
@@ -1,9 +1,11 @@
 async function submitbtn() {
     let operation = document.getElementById("operations").value;
-    let val1 = +document.getElementById("1").value;
-    let val2 = +document.getElementById("2").value;
+    // Sent as text so the exact modes see every digit that was typed.
+    let val1 = document.getElementById("1").value;
+    let val2 = document.getElementById("2").value;
+    let mode = document.getElementById("mode").value;
 
-    const response = await fetch("http://localhost:3030/", {
+    const response = await fetch("/api/v1/calculate", {
         method: "POST",
         headers: {
             "Content-Type": "application/json",
@@ -12,19 +14,25 @@ async function submitbtn() {
             value1: val1,
             value2: val2,
             operation: operation,
+            mode: mode,
+            ieee: document.getElementById("ieee").checked,
         }),
     });
 
+    let label = document.getElementById("res");
+
+    if (response.status == 400 || response.status == 422) {
+        label.textContent = "Error: " + (await response.json()).error;
+        return;
+    }
     if (!response.ok) {
         throw new Error(`Response status: ${response.status}`);
     }
-  
-    let label = document.getElementById("res");
 
     const json = await response.json();
     console.log(json);
 
-    label.innerHTML = "Result:" + json.value;
+    label.textContent = "Result:" + json.value;
 
 
     // console.log('hello');
@@ -0,0 +1,225 @@
+//! `POST /api/v1/calculate`.
+//!
+//! The body names two operands and one of the operators `+ - * / ^`:
+//!
+//! ```json
+//! { "value1": 2, "value2": "0.1", "operation": "^", "mode": "decimal" }
+//! ```
+//!
+//! `mode`, `scale` and `ieee` are optional; see `calc_core::numeric`. A good request
+//! gets `{ "value": ..., "mode": ... }`, anything else a JSON error:
+//!
+//! | status | code                | when                                        |
+//! |--------|---------------------|---------------------------------------------|
+//! | 400    | `bad_request`       | malformed body or operand                   |
+//! | 400    | `unknown_operation` | `operation` is not one of `+ - * / ^`       |
+//! | 404    | `not_found`         | unknown path under `/api`                   |
+//! | 405    | `method_not_allowed`| known path, wrong HTTP method               |
+//! | 413    | `payload_too_large` | body over `body_limit`                      |
+//! | 422    | `division_by_zero`, `overflow`, `not_a_number` | see `calc_core::numeric` |
+
+use std::convert::Infallible;
+
+use serde_derive::{Deserialize, Serialize};
+use warp::http::StatusCode;
+use warp::reply::{Reply, Response};
+use warp::Filter;
+
+use calc_core::expr::BinaryOp;
+use calc_core::numeric::{self, JsonNumber, Limits, Mode, Operand};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MathOperation {
+    pub value1: Operand,
+    pub value2: Operand,
+    pub operation: char,
+    #[serde(default)]
+    pub mode: Mode,
+    /// Digits after the point in `decimal` mode; the configured default if absent.
+    pub scale: Option<u32>,
+    /// Return `inf`, `-inf` and `NaN` instead of errors; see `numeric`.
+    #[serde(default)]
+    pub ieee: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MathResult {
+    pub value: JsonNumber,
+    pub mode: Mode,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MathError {
+    #[serde(skip)]
+    pub status: StatusCode,
+    pub code: &'static str,
+    pub error: String,
+}
+
+impl MathResult {
+    pub fn new(result: JsonNumber, mode: Mode) -> MathResult {
+        MathResult {
+            value: result,
+            mode,
+        }
+    }
+}
+
+impl MathError {
+    pub fn new(status: StatusCode, code: &'static str, error: impl Into<String>) -> MathError {
+        MathError {
+            status,
+            code,
+            error: error.into(),
+        }
+    }
+
+    fn into_response(self) -> Response {
+        warp::reply::with_status(warp::reply::json(&self), self.status).into_response()
+    }
+}
+
+impl From<numeric::Error> for MathError {
+    fn from(err: numeric::Error) -> MathError {
+        let status = if err.is_arithmetic() { StatusCode::UNPROCESSABLE_ENTITY } else { StatusCode::BAD_REQUEST };
+        MathError::new(status, err.code(), err.to_string())
+    }
+}
+
+pub fn calculate(data: &MathOperation, limits: Limits) -> Result<MathResult, MathError> {
+    let op = BinaryOp::from_symbol(data.operation).ok_or_else(|| {
+        let symbols: Vec<String> = BinaryOp::ALL.iter().map(|op| op.symbol().to_string()).collect();
+        MathError::new(
+            StatusCode::BAD_REQUEST,
+            "unknown_operation",
+            format!("unknown operation '{}', expected one of {}", data.operation, symbols.join(" ")),
+        )
+    })?;
+    let context = limits.context(data.mode, data.scale, data.ieee)?;
+    let value = context.apply(op, &context.operand(&data.value1)?, &context.operand(&data.value2)?)?;
+    let (result, _) = context.render(&context.finish(value));
+    Ok(MathResult::new(result, context.mode))
+}
+
+/// Everything under `/api`, with its rejections turned into JSON errors.
+/// Other paths are left to the site.
+pub fn routes(limits: Limits, body_limit: u64) -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone {
+    let calculate = warp::path!("v1" / "calculate")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(body_limit))
+        .and(warp::body::json())
+        .map(move |data: MathOperation| match calculate(&data, limits) {
+            Ok(result) => warp::reply::json(&result).into_response(),
+            Err(err) => err.into_response(),
+        });
+
+    warp::path("api").and(calculate.recover(handle_rejection).unify())
+}
+
+async fn handle_rejection(err: warp::Rejection) -> Result<Response, Infallible> {
+    let error = if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        MathError::new(StatusCode::BAD_REQUEST, "bad_request", e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::UnsupportedMediaType>() {
+        MathError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type", e.to_string())
+    } else if err.find::<warp::reject::LengthRequired>().is_some() {
+        MathError::new(StatusCode::BAD_REQUEST, "bad_request", "missing content-length")
+    } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        MathError::new(StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", "payload too large")
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        MathError::new(StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", "method not allowed")
+    } else if err.is_not_found() {
+        MathError::new(StatusCode::NOT_FOUND, "not_found", "endpoint not found")
+    } else {
+        // The details are warp's internals; they go to the log, not to the client.
+        log::error!("unhandled rejection: {err:?}");
+        MathError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", "internal error")
+    };
+    Ok(error.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn operation(value1: f64, operation: char, value2: f64) -> MathOperation {
+        MathOperation {
+            value1: Operand::Number(value1),
+            value2: Operand::Number(value2),
+            operation,
+            mode: Mode::F64,
+            scale: None,
+            ieee: false,
+        }
+    }
+
+    fn value(data: MathOperation) -> JsonNumber {
+        calculate(&data, Limits::default()).unwrap().value
+    }
+
+    fn code(data: MathOperation) -> &'static str {
+        calculate(&data, Limits::default()).unwrap_err().code
+    }
+
+    #[test]
+    fn applies_every_operation() {
+        assert_eq!(value(operation(7.0, '+', 2.0)), JsonNumber::Number(9.0));
+        assert_eq!(value(operation(7.0, '-', 2.0)), JsonNumber::Number(5.0));
+        assert_eq!(value(operation(7.0, '*', 2.0)), JsonNumber::Number(14.0));
+        assert_eq!(value(operation(7.0, '/', 2.0)), JsonNumber::Number(3.5));
+        assert_eq!(value(operation(7.0, '^', 2.0)), JsonNumber::Number(49.0));
+    }
+
+    #[test]
+    fn rejects_unknown_operation() {
+        let err = calculate(&operation(1.0, '%', 2.0), Limits::default()).unwrap_err();
+        assert_eq!((err.status, err.code), (StatusCode::BAD_REQUEST, "unknown_operation"));
+    }
+
+    #[test]
+    fn reports_arithmetic_errors() {
+        assert_eq!(code(operation(1.0, '/', 0.0)), "division_by_zero");
+        assert_eq!(code(operation(0.0, '^', -1.0)), "division_by_zero");
+        assert_eq!(code(operation(10.0, '^', 400.0)), "overflow");
+        assert_eq!(code(MathOperation { value1: Operand::Text("x".to_string()), ..operation(0.0, '+', 1.0) }), "bad_request");
+        assert_eq!(value(MathOperation { ieee: true, ..operation(1.0, '/', 0.0) }), JsonNumber::Text("inf".to_string()));
+    }
+
+    #[test]
+    fn exact_modes_keep_precision() {
+        assert_eq!(value(MathOperation { mode: Mode::Decimal, ..operation(0.1, '+', 0.2) }), JsonNumber::Number(0.3));
+        assert_eq!(value(MathOperation { mode: Mode::Rational, ..operation(1.0, '/', 3.0) }), JsonNumber::Text("1/3".to_string()));
+        assert_eq!(code(MathOperation { mode: Mode::Rational, ..operation(2.0, '^', 0.5) }), "bad_request");
+    }
+
+    #[tokio::test]
+    async fn routes_answer_with_json_errors() {
+        let api = routes(Limits::default(), 1024);
+
+        let response = warp::test::request().method("POST").path("/api/v1/calculate")
+            .json(&serde_json::json!({ "value1": 1, "value2": 2, "operation": "+" }))
+            .reply(&api).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body().as_ref(), br#"{"value":3.0,"mode":"f64"}"#);
+
+        let response = warp::test::request().method("POST").path("/api/v1/calculate")
+            .body(r#"{"value1": 1}"#).header("content-type", "application/json")
+            .reply(&api).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = warp::test::request().method("POST").path("/api/v1/calculate")
+            .body(r#"{"value1": 1, "value2": 2, "operation": "+"}"#).header("content-type", "text/plain")
+            .reply(&api).await;
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let response = warp::test::request().method("POST").path("/api/v1/calculate")
+            .json(&serde_json::json!({ "value1": 1, "value2": 0, "operation": "/" }))
+            .reply(&api).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let response = warp::test::request().method("POST").path("/api/v2/calculate").reply(&api).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(!warp::test::request().method("POST").path("/").matches(&api).await);
+        let response = warp::test::request().method("GET").path("/api/v1/calculate").reply(&api).await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
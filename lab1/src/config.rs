@@ -0,0 +1,185 @@
+//! Server configuration.
+//!
+//! Values are layered, later sources winning: built-in defaults, the TOML
+//! file (`config.toml` unless `--config` says otherwise), `LAB1_*`
+//! environment variables, then command-line flags.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use serde_derive::Deserialize;
+
+use calc_core::numeric::Limits;
+
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+#[derive(Debug, Parser)]
+#[command(about = "Calculator web server")]
+struct Args {
+    /// Path to the TOML configuration file [default: config.toml if it exists]
+    #[arg(long, env = "LAB1_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Address to listen on, e.g. 127.0.0.1:3030
+    #[arg(long, env = "LAB1_BIND")]
+    bind: Option<String>,
+
+    /// Directory with site.html, script.js and wrong_door.html
+    #[arg(long, env = "LAB1_STATIC_ROOT")]
+    static_root: Option<PathBuf>,
+
+    /// Maximum accepted request body, in bytes
+    #[arg(long, env = "LAB1_BODY_LIMIT")]
+    body_limit: Option<u64>,
+
+    /// Digits after the point in decimal mode when a request gives no scale
+    #[arg(long, env = "LAB1_DEFAULT_SCALE")]
+    default_scale: Option<u32>,
+
+    /// Largest scale a request may ask for
+    #[arg(long, env = "LAB1_MAX_SCALE")]
+    max_scale: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: String,
+    pub static_root: PathBuf,
+    pub body_limit: u64,
+    pub default_scale: u32,
+    pub max_scale: u32,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        let limits = Limits::default();
+        Config {
+            bind: "127.0.0.1:3030".to_string(),
+            static_root: PathBuf::from("./files/"),
+            body_limit: 1024 * 16,
+            default_scale: limits.default_scale,
+            max_scale: limits.max_scale,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Read(path, err) => write!(f, "cannot read config file {}: {err}", path.display()),
+            Error::Parse(path, err) => write!(f, "invalid config file {}: {err}", path.display()),
+            Error::Invalid(message) => write!(f, "invalid configuration: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Config {
+    /// Loads the configuration from the command line, environment and file.
+    pub fn load() -> Result<Config, Error> {
+        Config::from_args(Args::parse())
+    }
+
+    /// Layers `args`, which clap has already merged with the environment,
+    /// over the file, and validates only the result.
+    fn from_args(args: Args) -> Result<Config, Error> {
+        let mut config = match &args.config {
+            Some(path) => Config::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => Config::default(),
+        };
+
+        if let Some(bind) = args.bind {
+            config.bind = bind;
+        }
+        if let Some(static_root) = args.static_root {
+            config.static_root = static_root;
+        }
+        if let Some(body_limit) = args.body_limit {
+            config.body_limit = body_limit;
+        }
+        if let Some(default_scale) = args.default_scale {
+            config.default_scale = default_scale;
+        }
+        if let Some(max_scale) = args.max_scale {
+            config.max_scale = max_scale;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, Error> {
+        let text = std::fs::read_to_string(path).map_err(|err| Error::Read(path.to_owned(), err))?;
+        toml::from_str(&text).map_err(|err| Error::Parse(path.to_owned(), err))
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        self.bind_addr()?;
+
+        for file in ["site.html", "script.js", "wrong_door.html"] {
+            if !self.static_root.join(file).is_file() {
+                return Err(Error::Invalid(format!(
+                    "static_root {} has no {file}",
+                    self.static_root.display()
+                )));
+            }
+        }
+        if self.body_limit == 0 {
+            return Err(Error::Invalid("body_limit must be greater than 0".to_string()));
+        }
+        // Past a few thousand digits every division hits `numeric::MAX_BITS`.
+        if self.max_scale > 10_000 || self.default_scale > self.max_scale {
+            return Err(Error::Invalid("default_scale must not exceed max_scale, which must be at most 10000".to_string()));
+        }
+
+        Ok(())
+    }
+
+    pub fn limits(&self) -> Limits {
+        Limits {
+            default_scale: self.default_scale,
+            max_scale: self.max_scale,
+        }
+    }
+
+    pub fn bind_addr(&self) -> Result<SocketAddr, Error> {
+        self.bind.parse().map_err(|_| {
+            Error::Invalid(format!("bind '{}' is not an address like 127.0.0.1:3030", self.bind))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(file: &str, flags: &[&str]) -> Result<Config, Error> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, file).unwrap();
+        let path = path.to_str().unwrap();
+        let args = Args::try_parse_from(["lab1", "--config", path].iter().chain(flags)).unwrap();
+        Config::from_args(args)
+    }
+
+    #[test]
+    fn scale_flags_override_the_file_before_validation() {
+        let config = load("default_scale = 50\nmax_scale = 100\n", &["--max-scale", "60"]).unwrap();
+        assert_eq!((config.default_scale, config.max_scale), (50, 60));
+        assert!(matches!(load("default_scale = 50\n", &["--max-scale", "30"]), Err(Error::Invalid(_))));
+        let config = load("default_scale = 50\nmax_scale = 100\n", &["--default-scale", "10", "--max-scale", "30"]).unwrap();
+        assert_eq!((config.default_scale, config.max_scale), (10, 30));
+    }
+}
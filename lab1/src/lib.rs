@@ -0,0 +1,4 @@
+//! lab1's calculator API. The binary serves it next to the static site;
+//! lab2's tests drive it to check both servers calculate alike.
+
+pub mod api;
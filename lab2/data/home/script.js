@@ -26,11 +26,42 @@ async function naming(name){
         par.innerHTML="Your hash:"+ data.hash.substr(0,5);
     }
 }
+//OPERATORS
+async function fetchOperators() {
+    const response = await fetch(ser_fetch+"/api/operators", {
+        method: "GET",
+    });
+    if (!response.ok) {
+        throw new Error(`Response status: ${response.status}`);
+    }
+    const json = await response.json();
+    return json.operators;
+}
+
+async function loadOperators() {
+    const select = document.getElementById("operations");
+    if (!select) {
+        return;
+    }
+    const operators = await fetchOperators();
+    select.innerHTML = '';
+    operators.forEach(operator => {
+        const option = document.createElement('option');
+        option.value = operator.id;
+        option.textContent = operator.symbol;
+        option.title = operator.name;
+        select.appendChild(option);
+    });
+}
+document.addEventListener("DOMContentLoaded", loadOperators);
+
 //SUBMIT OPERATION
 async function submitbtn() {
     let operation = +document.getElementById("operations").value;
-    let val1 = +document.getElementById("1").value;
-    let val2 = +document.getElementById("2").value;
+    // Sent as text so the exact modes see every digit that was typed.
+    let val1 = document.getElementById("1").value;
+    let val2 = document.getElementById("2").value;
+    let mode = document.getElementById("mode").value;
     let result;
     if (val1!="" && val2!=""){
         const response = await fetch(ser_fetch+"/api/calculate", {
@@ -42,17 +73,18 @@ async function submitbtn() {
                 num1: val1,
                 num2: val2,
                 operator_id: operation,
-                result: null,
+                mode: mode,
+                ieee: document.getElementById("ieee").checked,
             }),
         });
 
-        if (!response.ok) {
-            throw new Error(`Response status: ${response.status}`);
-        }
-
         let label = document.getElementById("res");
 
         const json = await response.json();
+        if (!response.ok) {
+            label.innerHTML = json.error.message;
+            return;
+        }
         console.log(json);
         label.innerHTML = "Result: " + json.result;
     }
@@ -61,6 +93,39 @@ async function submitbtn() {
     }
     //saveOperation(`${val1} ${operation} ${val2} = ${result}`);
 }
+//EVALUATE EXPRESSION
+async function evaluatebtn() {
+    let expression = document.getElementById("expression").value;
+    if (expression.trim() == "") {
+        alert("Expression is empty!");
+        return;
+    }
+    let label = document.getElementById("expression_res");
+    const response = await fetch(ser_fetch+"/api/evaluate", {
+        method: "POST",
+        headers: {
+            "Content-Type": "application/json",
+        },
+        body: JSON.stringify({
+            expression: expression,
+            mode: document.getElementById("mode").value,
+            ieee: document.getElementById("ieee").checked,
+        }),
+    });
+
+    if (response.status == 400 || response.status == 422) {
+        const json = await response.json();
+        label.innerHTML = "Error: " + json.error.message;
+        return;
+    }
+    if (!response.ok) {
+        throw new Error(`Response status: ${response.status}`);
+    }
+
+    const json = await response.json();
+    console.log(json);
+    label.innerHTML = "Result: " + json.result;
+}
 //HISTORY
 async function saveOperation(operation) {
         const response = await fetch(ser_fetch+"/api/history", {
@@ -87,12 +152,48 @@ async function clearHistory(){
 
         const historyList = document.getElementById("history");
         historyList.innerHTML = "";
-        alert("History cleared successfully!");
+        document.getElementById("more").hidden = true;
+        showUndo(await response.json());
     } catch (error) {
         console.error("Failed to clear history:", error);
         alert("Error: Unable to clear history. Please try again.");
     }
 }
+
+async function deleteHistoryEntry(id, li){
+    const response = await fetch(ser_fetch + "/api/history/" + id, {
+        method: "DELETE",
+    });
+    if (!response.ok) {
+        console.error(`Response status: ${response.status}`);
+        return;
+    }
+    li.remove();
+    showUndo(await response.json());
+}
+
+let undoTimer = null;
+
+// Shows the Undo button until the server's undo window runs out.
+function showUndo(deleted){
+    const undo = document.getElementById("undo");
+    undo.hidden = false;
+    clearTimeout(undoTimer);
+    undoTimer = setTimeout(() => undo.hidden = true, deleted.undo_until * 1000 - Date.now());
+}
+
+async function undoDelete(){
+    const response = await fetch(ser_fetch + "/api/history/undo", {
+        method: "POST",
+    });
+    if (!response.ok) {
+        console.error(`Response status: ${response.status}`);
+        return;
+    }
+    clearTimeout(undoTimer);
+    document.getElementById("undo").hidden = true;
+    displayHistory();
+}
 //LOGIN
 async function login(){
 
@@ -107,9 +208,13 @@ async function login(){
         body: JSON.stringify({
             name: firstName,
             password: password,
+            adopt_history: document.getElementById("adoptHistory").checked,
         }),
     });
-    if (!response.ok) {
+    if (response.status === 429) {
+        label.innerHTML=`Too many attempts, try again in ${response.headers.get("Retry-After")} seconds.`;
+    }
+    else if (!response.ok) {
         
         label.innerHTML="Inccorrect password or login!";
         
@@ -124,7 +229,7 @@ document.addEventListener("DOMContentLoaded", naming);
 //DUMP
 async function downloadDump(){
     try {
-        const response = await fetch(ser_fetch+"/api/export_users", {
+        const response = await fetch(ser_fetch+"/api/export_users?include_hashes=true", {
             method: "GET",
             headers: {
                 "Content-Type": "application/octet-stream"
@@ -140,7 +245,7 @@ async function downloadDump(){
         const link = document.createElement('a');
         link.href = window.URL.createObjectURL(blob);
 
-        link.download = 'users_dump.sql';
+        link.download = 'users_dump.json';
         link.style.display = 'none';
 
         document.body.appendChild(link);
@@ -151,7 +256,7 @@ async function downloadDump(){
     }
 }
 
-/*async function uploadDump(event) {
+async function uploadDump(event) {
     event.preventDefault(); 
     const fileInput = document.getElementById('file-upload');
     const selectedFile = fileInput.files[0]; 
@@ -160,24 +265,35 @@ async function downloadDump(){
         return;
     }
 
-    const formData = new FormData();
-    formData.append('file', selectedFile);
+    const format = selectedFile.name.toLowerCase().endsWith('.csv') ? 'csv' : 'json';
+    const on_conflict = document.getElementById('on-conflict').value;
+    const dry_run = document.getElementById('dry-run').checked;
+    const label = document.getElementById('import-result');
 
     try {
-        const response = await fetch(`${ser_fetch}/api/import_users`, {
+        const response = await fetch(`${ser_fetch}/api/import_users?format=${format}&on_conflict=${on_conflict}&dry_run=${dry_run}`, {
             method: 'POST',
-            body: formData,
+            body: selectedFile,
         });
 
+        const result = await response.json();
         if (!response.ok) {
-            throw new Error(`Server error: ${response.status}`);
+            label.textContent = result.error.message;
+            return;
         }
 
-        const result = await response.json();
+        const failed = result.rows.filter(row => row.message && row.status != 'skipped')
+            .map(row => `row ${row.row} (${row.name}): ${row.message}`);
+        label.textContent = `created ${result.created}, updated ${result.updated}, skipped ${result.skipped}, failed ${result.failed}`
+            + (result.applied ? '' : ' (nothing saved)')
+            + (failed.length ? '; ' + failed.join('; ') : '');
+        if (result.applied) {
+            users_table();
+        }
     } catch (error) {
         console.error('Error uploading dump file:', error);
     }
-}*/
+}
 
 //REGISTRATION
 async function reg(){
@@ -195,7 +311,8 @@ async function reg(){
                 },
                 body: JSON.stringify({
                     name: firstName,
-                    password: password
+                    password: password,
+                    adopt_history: document.getElementById('adoptHistory').checked,
                 }),
             });
 
@@ -211,7 +328,7 @@ async function reg(){
 }
 async function logout() {
     const response = await fetch(ser_fetch+"/api/logout", {
-        method: "GET",
+        method: "POST",
     });
     if (!response.ok) {
         throw new Error(`Response status: ${response.status}`);
@@ -220,11 +337,28 @@ async function logout() {
         window.location.href="/";
     }
 }
+async function logoutEverywhere() {
+    const response = await fetch(ser_fetch+"/api/logout_everywhere", {
+        method: "POST",
+    });
+    if (!response.ok) {
+        const body = await response.json().catch(() => null);
+        alert(body?.error?.message ?? `Response status: ${response.status}`);
+        return;
+    }
+    window.location.href="/";
+}
 
 //HISTORY
 
-async function displayHistory() {  
-    const response = await fetch(ser_fetch+"/api/history", {
+let historyCursor = null;
+
+async function displayHistory(more = false) {
+    const params = new URLSearchParams();
+    if (more && historyCursor) {
+        params.set("cursor", historyCursor);
+    }
+    const response = await fetch(ser_fetch+"/api/history?"+params, {
         method: "GET",
     });
     
@@ -232,27 +366,31 @@ async function displayHistory() {
         throw new Error(`Response status: ${response.status}`);
     }
     historyList=document.getElementById("history");
-    historyList.innerHTML='';
+    if (!more) {
+        historyList.innerHTML='';
+    }
     const history = await response.json();
+    const symbols = {};
+    (await fetchOperators()).forEach(operator => symbols[operator.id] = operator.symbol);
     history.history.forEach(response => {
         const li = document.createElement('li');
-        switch (response.operator_id){
-            case 1:
-                response.operator_id="+";
-                break;
-            case 2:
-                response.operator_id="-";
-                break;
-            case 3:
-                response.operator_id="*";
-                break;
-            case 4:
-                response.operator_id="/";
-                break;
+        const result = response.exact_result ?? response.result;
+        if (response.expression) {
+            li.textContent = `${response.expression} = ${result}`;
+        } else {
+            li.textContent = `${response.num1} ${symbols[response.operator_id]} ${response.num2} = ${result}`;
+        }
+        if (response.created_at) {
+            li.title = new Date(response.created_at * 1000).toLocaleString();
         }
-        li.textContent = `${response.num1} ${response.operator_id} ${response.num2} = ${response.result}`;
+        const remove = document.createElement('button');
+        remove.textContent = '✕';
+        remove.onclick = () => deleteHistoryEntry(response.id, li);
+        li.appendChild(remove);
         historyList.appendChild(li);
     });
+    historyCursor = history.next_cursor;
+    document.getElementById("more").hidden = !historyCursor;
     
     console.log(history);
     
@@ -368,8 +506,9 @@ async function users_table() {
             method: "GET",
         });
         
-        if (response.status==228) {
-            throw new Error(`Response status: ${response.status}`);
+        if (!response.ok) {
+            const json = await response.json();
+            throw new Error(`${response.status}: ${json.error.message}`);
         }
         const userList = document.getElementById("users");
         userList.innerHTML = ''; 
@@ -394,8 +533,9 @@ async function users_table() {
                         },
                     });
                     
-                    if (response.status==228) {
-                        throw new Error(`Response status: ${response.status}`);
+                    if (!response.ok) {
+                        const json = await response.json();
+                        throw new Error(`${response.status}: ${json.error.message}`);
                     }
   
                 } catch (error) {
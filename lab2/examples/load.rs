@@ -0,0 +1,125 @@
+//! Load generator for a running lab2 server.
+//!
+//! ```text
+//! cargo run --release --example load -- [addr] [connections] [seconds] [endpoint]
+//! ```
+//!
+//! Defaults to `127.0.0.1:2017`, 32 connections and 10 seconds. `endpoint`
+//! is `calculate` (the default), `history` or `mixed` (alternating). Each
+//! connection gets its own session cookie from `/` and then sends requests
+//! back to back over keep-alive, like a browser tab would. Prints requests
+//! per second and how many were not answered `200`.
+
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+const CALCULATE: &str = r#"{"num1": 6, "num2": 7, "operator_id": 3}"#;
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let arg = |index: usize, default: &str| args.get(index).cloned().unwrap_or_else(|| default.to_string());
+    let addr = arg(0, "127.0.0.1:2017");
+    let connections: usize = arg(1, "32").parse().expect("connections is a number");
+    let duration = Duration::from_secs(arg(2, "10").parse().expect("seconds is a number"));
+    let endpoint = arg(3, "calculate");
+    if !["calculate", "history", "mixed"].contains(&endpoint.as_str()) {
+        panic!("unknown endpoint {endpoint}, expected calculate, history or mixed");
+    }
+
+    let deadline = Instant::now() + duration;
+    let workers: Vec<_> = (0..connections)
+        .map(|_| tokio::spawn(worker(addr.clone(), endpoint.clone(), deadline)))
+        .collect();
+    let (mut answered, mut failed) = (0u64, 0u64);
+    for worker in workers {
+        let (ok, not_ok) = worker.await.unwrap().unwrap_or_else(|err| panic!("connection to {addr} failed: {err}"));
+        answered += ok;
+        failed += not_ok;
+    }
+
+    let total = answered + failed;
+    println!(
+        "{endpoint}: {total} requests over {connections} connections in {}s, {:.0} req/s, {failed} not 200",
+        duration.as_secs(),
+        total as f64 / duration.as_secs_f64(),
+    );
+}
+
+/// Sends requests until `deadline`. Returns how many were answered `200`
+/// and how many were not.
+async fn worker(addr: String, endpoint: String, deadline: Instant) -> std::io::Result<(u64, u64)> {
+    let mut conn = BufReader::new(TcpStream::connect(&addr).await?);
+    let (_, headers) = exchange(&mut conn, &request(&addr, "GET", "/", None, None)).await?;
+    let cookie = headers
+        .iter()
+        .find_map(|(name, value)| (name == "set-cookie").then_some(value)?.strip_prefix("session_hash="))
+        .and_then(|value| value.split(';').next())
+        .expect("the server hands out a session cookie")
+        .to_string();
+
+    let calculate = request(&addr, "POST", "/api/calculate", Some(&cookie), Some(CALCULATE));
+    let history = request(&addr, "GET", "/api/history", Some(&cookie), None);
+    let (mut ok, mut not_ok) = (0, 0);
+    let mut sent = 0u64;
+    while Instant::now() < deadline {
+        let request = match endpoint.as_str() {
+            "calculate" => &calculate,
+            "history" => &history,
+            _ if sent.is_multiple_of(2) => &calculate,
+            _ => &history,
+        };
+        let (status, _) = exchange(&mut conn, request).await?;
+        if status == 200 {
+            ok += 1;
+        } else {
+            not_ok += 1;
+        }
+        sent += 1;
+    }
+    Ok((ok, not_ok))
+}
+
+fn request(host: &str, method: &str, path: &str, cookie: Option<&str>, body: Option<&str>) -> Vec<u8> {
+    let mut request = format!("{method} {path} HTTP/1.1\r\nhost: {host}\r\n");
+    if let Some(cookie) = cookie {
+        request.push_str(&format!("cookie: session_hash={cookie}\r\n"));
+    }
+    if let Some(body) = body {
+        request.push_str(&format!("content-type: application/json\r\ncontent-length: {}\r\n\r\n{body}", body.len()));
+    } else {
+        request.push_str("\r\n");
+    }
+    request.into_bytes()
+}
+
+/// Writes `request` and reads one response, returning its status and its
+/// headers with lowercased names. Bodies must come with a `content-length`.
+async fn exchange(conn: &mut BufReader<TcpStream>, request: &[u8]) -> std::io::Result<(u16, Vec<(String, String)>)> {
+    conn.get_mut().write_all(request).await?;
+
+    let mut line = String::new();
+    conn.read_line(&mut line).await?;
+    let status = line.split(' ').nth(1).and_then(|code| code.parse().ok()).unwrap_or(0);
+
+    let mut headers = Vec::new();
+    let mut length = 0;
+    loop {
+        line.clear();
+        conn.read_line(&mut line).await?;
+        let Some((name, value)) = line.trim_end().split_once(':') else {
+            break;
+        };
+        let (name, value) = (name.to_ascii_lowercase(), value.trim().to_string());
+        if name == "content-length" {
+            length = value.parse().unwrap_or(0);
+        }
+        headers.push((name, value));
+    }
+
+    let mut body = vec![0; length];
+    conn.read_exact(&mut body).await?;
+    Ok((status, headers))
+}
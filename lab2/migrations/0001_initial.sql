@@ -0,0 +1,42 @@
+-- Schema as it existed before migrations were introduced. Every statement
+-- is idempotent so databases created by hand are adopted as version 1.
+
+create table if not exists users(
+    id integer primary key autoincrement,
+    name string not null,
+    auth_hash string not null,
+    role
+);
+
+create table if not exists sessions(
+    id integer primary key autoincrement,
+    hash string not null,
+    is_auth bool not null,
+    user_id int,
+    name string,
+    foreign key(user_id) references users(id)
+);
+
+create table if not exists operators(
+    id integer primary key autoincrement,
+    name string
+);
+
+create table if not exists calculations(
+    id integer primary key autoincrement,
+    num1 float not null,
+    num2 float not null,
+    operator_id int not null,
+    result float not null,
+    session_id int not null,
+    user_id int,
+    foreign key(user_id) references users(id),
+    foreign key(session_id) references sessions(id),
+    foreign key(operator_id) references operators(id)
+);
+
+insert or ignore into operators(id, name) values
+    (1, 'Addition'),
+    (2, 'Subtraction'),
+    (3, 'Multiplication'),
+    (4, 'Division');
@@ -0,0 +1,24 @@
+-- Calculations made by `/api/evaluate` store the expression text and have no
+-- operands or operator. SQLite cannot drop a NOT NULL constraint, so the
+-- table is rebuilt with those columns relaxed.
+
+create table calculations_new(
+    id integer primary key autoincrement,
+    num1 float,
+    num2 float,
+    operator_id int,
+    result float not null,
+    session_id int not null,
+    user_id int,
+    expression string,
+    foreign key(user_id) references users(id),
+    foreign key(session_id) references sessions(id),
+    foreign key(operator_id) references operators(id)
+);
+
+insert into calculations_new(id, num1, num2, operator_id, result, session_id, user_id)
+select id, num1, num2, operator_id, result, session_id, user_id
+from calculations;
+
+drop table calculations;
+alter table calculations_new rename to calculations;
@@ -0,0 +1,7 @@
+-- Unix timestamps (seconds) used to expire sessions. Sessions that existed
+-- before this migration are treated as created now.
+
+alter table sessions add column created_at integer not null default 0;
+alter table sessions add column last_seen_at integer not null default 0;
+
+update sessions set created_at = unixepoch(), last_seen_at = unixepoch();
@@ -0,0 +1,22 @@
+-- Roles and the permissions they grant. `users.role` holds a role name.
+
+create table roles(
+    name string primary key
+);
+
+create table role_permissions(
+    role string not null,
+    permission string not null,
+    primary key(role, permission),
+    foreign key(role) references roles(name)
+);
+
+insert into roles(name) values ('moderling'), ('normise');
+
+insert into role_permissions(role, permission) values
+    ('moderling', 'view_users'),
+    ('moderling', 'delete_users'),
+    ('moderling', 'export_users'),
+    ('moderling', 'import_users');
+
+update users set role = 'normise' where role is null or role not in (select name from roles);
@@ -0,0 +1,7 @@
+-- `sessions.hash` now stores the SHA-256 of the cookie value instead of the
+-- cookie itself. Older rows hold raw cookie values, so they are disabled and
+-- left for the cleanup task; their owners get a new session on next visit.
+
+update sessions set hash = 'legacy:' || id, created_at = 0, last_seen_at = 0;
+
+create unique index sessions_hash on sessions(hash);
@@ -0,0 +1,15 @@
+-- Turn `operators` into the registry behind `/api/calculate`. `symbol` picks
+-- the implementation in the expression engine; `arity` and `precedence` are
+-- checked against it when the server starts.
+
+alter table operators add column symbol string;
+alter table operators add column arity int not null default 2;
+alter table operators add column precedence int not null default 0;
+
+update operators set symbol = '+', precedence = 1 where id = 1;
+update operators set symbol = '-', precedence = 1 where id = 2;
+update operators set symbol = '*', precedence = 2 where id = 3;
+update operators set symbol = '/', precedence = 2 where id = 4;
+
+insert or ignore into operators(id, name, symbol, arity, precedence) values
+    (5, 'Exponentiation', '^', 2, 4);
@@ -0,0 +1,7 @@
+-- Creation time for calculations, and indexes for paging through a user's
+-- or session's history. Rows made before this have `created_at = 0`.
+
+alter table calculations add column created_at integer not null default 0;
+
+create index calculations_user_history on calculations(user_id, created_at, id);
+create index calculations_session_history on calculations(session_id, created_at, id);
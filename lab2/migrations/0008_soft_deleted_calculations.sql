@@ -0,0 +1,6 @@
+-- Deleted calculations are kept for an undo window before being purged.
+-- `deleted_at` is unix seconds, null while the calculation is in history.
+
+alter table calculations add column deleted_at integer;
+
+create index calculations_deleted on calculations(deleted_at) where deleted_at is not null;
@@ -0,0 +1,6 @@
+-- Numeric mode of each calculation and, for the exact modes, the exact
+-- result as text. `result` keeps the nearest double so history can still
+-- be sorted and filtered by it.
+
+alter table calculations add column mode text not null default 'f64';
+alter table calculations add column exact_result text;
@@ -0,0 +1,4 @@
+-- Logged-out sessions stay in the table because calculations refer to them,
+-- but a non-null `revoked_at` (unix seconds) stops their cookie working.
+
+alter table sessions add column revoked_at integer;
@@ -0,0 +1,5 @@
+-- The browser and address a session logged in from, shown to the user in
+-- their list of active sessions. Null for sessions that never logged in.
+
+alter table sessions add column user_agent string;
+alter table sessions add column ip string;
@@ -0,0 +1,28 @@
+-- Names identify accounts at login, so they must be unique. Duplicates left
+-- from before registration checked for them: the oldest account keeps the
+-- name, the others get their id appended, e.g. `ann#7`. If someone already
+-- has that name, a counter is appended as well: `ann#7-2`, `ann#7-3`, ...
+-- Different renamed accounts cannot produce the same name, since the digits
+-- after the last `#` are each one's unique id.
+
+create temp table renamed_users as
+with recursive
+duplicates(id, name) as (
+    select id, name from users
+    where id not in (select min(id) from users group by name)
+),
+attempts(id, name, n, candidate) as (
+    select id, name, 1, name || '#' || id from duplicates
+    union all
+    select id, name, n + 1, name || '#' || id || '-' || (n + 1) from attempts
+    where exists (select 1 from users where users.name = attempts.candidate)
+)
+select id, candidate from attempts
+where not exists (select 1 from users where users.name = attempts.candidate);
+
+update users set name = (select candidate from renamed_users where renamed_users.id = users.id)
+where id in (select id from renamed_users);
+
+drop table renamed_users;
+
+create unique index users_name on users(name);
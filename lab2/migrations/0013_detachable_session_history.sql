@@ -0,0 +1,32 @@
+-- Calculations owned by an account outlive the session they were made in:
+-- purging that session clears `session_id` instead. SQLite cannot drop a
+-- NOT NULL constraint, so the table is rebuilt and its indexes recreated.
+
+create table calculations_new(
+    id integer primary key autoincrement,
+    num1 float,
+    num2 float,
+    operator_id int,
+    result float not null,
+    session_id int,
+    user_id int,
+    expression string,
+    created_at integer not null default 0,
+    deleted_at integer,
+    mode text not null default 'f64',
+    exact_result text,
+    foreign key(user_id) references users(id),
+    foreign key(session_id) references sessions(id),
+    foreign key(operator_id) references operators(id)
+);
+
+insert into calculations_new(id, num1, num2, operator_id, result, session_id, user_id, expression, created_at, deleted_at, mode, exact_result)
+select id, num1, num2, operator_id, result, session_id, user_id, expression, created_at, deleted_at, mode, exact_result
+from calculations;
+
+drop table calculations;
+alter table calculations_new rename to calculations;
+
+create index calculations_user_history on calculations(user_id, created_at, id);
+create index calculations_session_history on calculations(session_id, created_at, id);
+create index calculations_deleted on calculations(deleted_at) where deleted_at is not null;
@@ -0,0 +1,495 @@
+//! Server configuration.
+//!
+//! Values are layered, later sources winning: built-in defaults, the TOML
+//! file (`config.toml` unless `--config` says otherwise), then overrides.
+//! Any setting can be overridden with a `LAB2_<SECTION>_<KEY>` environment
+//! variable or `--set <section>.<key>=<value>`, and the most common ones
+//! also have flags of their own (`--bind`, `--database`, ...), which win
+//! over both. Relative paths in the file are relative to the file's
+//! directory; relative paths from overrides are relative to the working
+//! directory. See `config.toml` for every setting and its default.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::Parser;
+use serde_derive::Deserialize;
+
+use calc_core::numeric::Limits;
+
+use crate::models::SessionLifetimes;
+use crate::password::HashCost;
+use crate::throttle::Policy;
+
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+/// The sections of `Config`, for splitting `LAB2_<SECTION>_<KEY>` names.
+const SECTIONS: [&str; 7] = ["server", "database", "sessions", "history", "numeric", "passwords", "login_limits"];
+
+#[derive(Debug, Parser)]
+#[command(about = "Calculator web server")]
+struct Args {
+    /// Path to the TOML configuration file [default: config.toml if it exists]
+    #[arg(long, env = "LAB2_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Address to listen on, e.g. 127.0.0.1:2017
+    #[arg(long, env = "LAB2_BIND")]
+    bind: Option<String>,
+
+    /// SQLite database file
+    #[arg(long, env = "LAB2_DATABASE")]
+    database: Option<PathBuf>,
+
+    /// Directory with the static pages, scripts and names.txt
+    #[arg(long, env = "LAB2_STATIC_ROOT")]
+    static_root: Option<PathBuf>,
+
+    /// Maximum accepted request body, in bytes
+    #[arg(long, env = "LAB2_BODY_LIMIT")]
+    body_limit: Option<u64>,
+
+    /// Overrides any setting, e.g. sessions.idle_timeout_secs=3600; repeatable
+    #[arg(long = "set", value_name = "SECTION.KEY=VALUE")]
+    set: Vec<String>,
+}
+
+/// One setting given in the environment or with `--set`.
+#[derive(Debug, Clone, PartialEq)]
+struct Override {
+    section: String,
+    key: String,
+    value: toml::Value,
+}
+
+impl Override {
+    /// `LAB2_<SECTION>_<KEY>`, or `None` for variables that are not
+    /// settings, such as `LAB2_BIND`.
+    fn from_env(name: &str, value: &str) -> Option<Override> {
+        let name = name.strip_prefix("LAB2_")?.to_ascii_lowercase();
+        let section = SECTIONS.iter().find(|section| name.starts_with(&format!("{section}_")))?;
+        Some(Override {
+            section: section.to_string(),
+            key: name[section.len() + 1..].to_string(),
+            value: parse_value(value),
+        })
+    }
+
+    /// `--set <section>.<key>=<value>`.
+    fn from_arg(arg: &str) -> Result<Override, Error> {
+        let invalid = || Error::Invalid(format!("--set {arg}: expected SECTION.KEY=VALUE, like server.bind=0.0.0.0:2017"));
+        let (name, value) = arg.split_once('=').ok_or_else(invalid)?;
+        let (section, key) = name.split_once('.').ok_or_else(invalid)?;
+        if !SECTIONS.contains(&section) {
+            return Err(Error::Invalid(format!("--set {arg}: there is no [{section}] section")));
+        }
+        Ok(Override { section: section.to_string(), key: key.to_string(), value: parse_value(value) })
+    }
+}
+
+/// Reads `raw` as a TOML value, or as a string when it is not one, so
+/// `LAB2_SERVER_BIND=0.0.0.0:2017` needs no quotes.
+fn parse_value(raw: &str) -> toml::Value {
+    toml::from_str::<toml::Table>(&format!("value = {raw}"))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(raw.to_string()))
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub sessions: SessionsConfig,
+    pub history: HistoryConfig,
+    pub numeric: NumericConfig,
+    pub passwords: PasswordsConfig,
+    pub login_limits: LoginLimitsConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    pub static_root: PathBuf,
+    pub body_limit: u64,
+    pub import_body_limit: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub path: PathBuf,
+    pub pool_size: u32,
+    pub busy_timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionsConfig {
+    pub absolute_lifetime_secs: u64,
+    pub idle_timeout_secs: u64,
+    pub cleanup_interval_secs: u64,
+    pub secure_cookie: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    pub undo_window_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NumericConfig {
+    pub default_scale: u32,
+    pub max_scale: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PasswordsConfig {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoginLimitsConfig {
+    pub ip_free_failures: u32,
+    pub account_free_failures: u32,
+    pub base_delay_secs: u64,
+    pub max_delay_secs: u64,
+    pub lockout_failures: u32,
+    pub lockout_secs: u64,
+    pub reset_after_secs: u64,
+    pub max_tracked: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            bind: "127.0.0.1:2017".to_string(),
+            static_root: PathBuf::from("./data/"),
+            body_limit: 1024 * 16,
+            import_body_limit: 1024 * 1024,
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> DatabaseConfig {
+        DatabaseConfig {
+            path: PathBuf::from("database.sqlitedb"),
+            pool_size: 8,
+            busy_timeout_ms: 5000,
+        }
+    }
+}
+
+impl Default for SessionsConfig {
+    fn default() -> SessionsConfig {
+        let lifetimes = SessionLifetimes::default();
+        SessionsConfig {
+            absolute_lifetime_secs: lifetimes.absolute.as_secs(),
+            idle_timeout_secs: lifetimes.idle.as_secs(),
+            cleanup_interval_secs: lifetimes.cleanup_interval.as_secs(),
+            secure_cookie: false,
+        }
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> HistoryConfig {
+        HistoryConfig {
+            undo_window_secs: 5 * 60,
+        }
+    }
+}
+
+impl Default for NumericConfig {
+    fn default() -> NumericConfig {
+        let limits = Limits::default();
+        NumericConfig {
+            default_scale: limits.default_scale,
+            max_scale: limits.max_scale,
+        }
+    }
+}
+
+impl Default for PasswordsConfig {
+    fn default() -> PasswordsConfig {
+        let cost = HashCost::default();
+        PasswordsConfig {
+            memory_kib: cost.memory_kib,
+            iterations: cost.iterations,
+            parallelism: cost.parallelism,
+        }
+    }
+}
+
+impl Default for LoginLimitsConfig {
+    fn default() -> LoginLimitsConfig {
+        let policy = Policy::default();
+        LoginLimitsConfig {
+            ip_free_failures: policy.ip_free_failures,
+            account_free_failures: policy.account_free_failures,
+            base_delay_secs: policy.base_delay.as_secs(),
+            max_delay_secs: policy.max_delay.as_secs(),
+            lockout_failures: policy.lockout_failures,
+            lockout_secs: policy.lockout.as_secs(),
+            reset_after_secs: policy.reset_after.as_secs(),
+            max_tracked: policy.max_tracked,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// An environment variable or `--set` the file would not accept either.
+    Override(toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Read(path, err) => write!(f, "cannot read config file {}: {err}", path.display()),
+            Error::Parse(path, err) => write!(f, "invalid config file {}: {err}", path.display()),
+            Error::Override(err) => write!(f, "invalid configuration override: {err}"),
+            Error::Invalid(message) => write!(f, "invalid configuration: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Config {
+    /// Loads the configuration from the command line, environment and file.
+    pub fn load() -> Result<Config, Error> {
+        let args = Args::parse();
+
+        let file = match args.config {
+            Some(path) => Some(path),
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Some(PathBuf::from(DEFAULT_CONFIG_FILE)),
+            None => None,
+        };
+        let mut overrides: Vec<Override> = std::env::vars_os()
+            .filter_map(|(name, value)| Override::from_env(name.to_str()?, value.to_str()?))
+            .collect();
+        for arg in &args.set {
+            overrides.push(Override::from_arg(arg)?);
+        }
+        let mut config = Config::from_sources(file.as_deref(), &overrides)?;
+
+        if let Some(bind) = args.bind {
+            config.server.bind = bind;
+        }
+        if let Some(database) = args.database {
+            config.database.path = database;
+        }
+        if let Some(static_root) = args.static_root {
+            config.server.static_root = static_root;
+        }
+        if let Some(body_limit) = args.body_limit {
+            config.server.body_limit = body_limit;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The file, if any, with `overrides` applied in order on top of it.
+    fn from_sources(file: Option<&Path>, overrides: &[Override]) -> Result<Config, Error> {
+        let mut table = match file {
+            Some(path) => {
+                let text = std::fs::read_to_string(path).map_err(|err| Error::Read(path.to_owned(), err))?;
+                // Parsed as a `Config` first so mistakes in the file are
+                // reported against it.
+                toml::from_str::<Config>(&text).map_err(|err| Error::Parse(path.to_owned(), err))?;
+                toml::from_str::<toml::Table>(&text).map_err(|err| Error::Parse(path.to_owned(), err))?
+            }
+            None => toml::Table::new(),
+        };
+        let from_file = |section: &str, key: &str| {
+            table.get(section).and_then(|values| values.get(key)).is_some()
+                && !overrides.iter().any(|o| o.section == section && o.key == key)
+        };
+        let (static_root_from_file, database_from_file) = (from_file("server", "static_root"), from_file("database", "path"));
+
+        for o in overrides {
+            let section = table.entry(o.section.clone()).or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            if let Some(section) = section.as_table_mut() {
+                section.insert(o.key.clone(), o.value.clone());
+            }
+        }
+        let mut config: Config = toml::Value::Table(table).try_into().map_err(Error::Override)?;
+
+        if let Some(dir) = file.and_then(Path::parent) {
+            if static_root_from_file {
+                config.server.static_root = dir.join(&config.server.static_root);
+            }
+            if database_from_file {
+                config.database.path = dir.join(&config.database.path);
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        self.bind_addr()?;
+
+        if !self.server.static_root.is_dir() {
+            return Err(Error::Invalid(format!(
+                "server.static_root {} is not a directory",
+                self.server.static_root.display()
+            )));
+        }
+        if !self.server.static_root.join("names.txt").is_file() {
+            return Err(Error::Invalid(format!(
+                "server.static_root {} has no names.txt",
+                self.server.static_root.display()
+            )));
+        }
+        if self.server.body_limit == 0 || self.server.import_body_limit == 0 {
+            return Err(Error::Invalid("server.body_limit and server.import_body_limit must be greater than 0".to_string()));
+        }
+
+        if self.database.pool_size == 0 || self.database.busy_timeout_ms == 0 {
+            return Err(Error::Invalid("database.pool_size and database.busy_timeout_ms must be greater than 0".to_string()));
+        }
+
+        let sessions = &self.sessions;
+        if sessions.absolute_lifetime_secs == 0 || sessions.idle_timeout_secs == 0 || sessions.cleanup_interval_secs == 0 {
+            return Err(Error::Invalid("sessions.* durations must be greater than 0".to_string()));
+        }
+        if sessions.idle_timeout_secs > sessions.absolute_lifetime_secs {
+            return Err(Error::Invalid(
+                "sessions.idle_timeout_secs must not exceed sessions.absolute_lifetime_secs".to_string(),
+            ));
+        }
+
+        if self.history.undo_window_secs == 0 {
+            return Err(Error::Invalid("history.undo_window_secs must be greater than 0".to_string()));
+        }
+
+        // Past a few thousand digits every division hits `numeric::MAX_BITS`.
+        if self.numeric.max_scale > 10_000 || self.numeric.default_scale > self.numeric.max_scale {
+            return Err(Error::Invalid("numeric.default_scale must not exceed numeric.max_scale, which must be at most 10000".to_string()));
+        }
+
+        crate::password::Passwords::new(self.hash_cost())
+            .map_err(|err| Error::Invalid(format!("passwords: {err}")))?;
+
+        let limits = &self.login_limits;
+        if limits.base_delay_secs == 0 || limits.max_delay_secs < limits.base_delay_secs {
+            return Err(Error::Invalid("login_limits.base_delay_secs must be greater than 0 and at most login_limits.max_delay_secs".to_string()));
+        }
+        if limits.lockout_failures <= limits.account_free_failures || limits.lockout_secs == 0 || limits.reset_after_secs == 0 {
+            return Err(Error::Invalid(
+                "login_limits.lockout_failures must exceed login_limits.account_free_failures, and lockout_secs and reset_after_secs must be greater than 0".to_string(),
+            ));
+        }
+        if limits.max_tracked == 0 {
+            return Err(Error::Invalid("login_limits.max_tracked must be greater than 0".to_string()));
+        }
+
+        Ok(())
+    }
+
+    pub fn bind_addr(&self) -> Result<SocketAddr, Error> {
+        self.server.bind.parse().map_err(|_| {
+            Error::Invalid(format!("server.bind '{}' is not an address like 127.0.0.1:2017", self.server.bind))
+        })
+    }
+
+    pub fn session_lifetimes(&self) -> SessionLifetimes {
+        SessionLifetimes {
+            absolute: Duration::from_secs(self.sessions.absolute_lifetime_secs),
+            idle: Duration::from_secs(self.sessions.idle_timeout_secs),
+            cleanup_interval: Duration::from_secs(self.sessions.cleanup_interval_secs),
+        }
+    }
+
+    pub fn undo_window(&self) -> Duration {
+        Duration::from_secs(self.history.undo_window_secs)
+    }
+
+    pub fn numeric_limits(&self) -> Limits {
+        Limits {
+            default_scale: self.numeric.default_scale,
+            max_scale: self.numeric.max_scale,
+        }
+    }
+
+    pub fn throttle_policy(&self) -> Policy {
+        let limits = &self.login_limits;
+        Policy {
+            ip_free_failures: limits.ip_free_failures,
+            account_free_failures: limits.account_free_failures,
+            base_delay: Duration::from_secs(limits.base_delay_secs),
+            max_delay: Duration::from_secs(limits.max_delay_secs),
+            lockout_failures: limits.lockout_failures,
+            lockout: Duration::from_secs(limits.lockout_secs),
+            reset_after: Duration::from_secs(limits.reset_after_secs),
+            max_tracked: limits.max_tracked,
+        }
+    }
+
+    pub fn hash_cost(&self) -> HashCost {
+        HashCost {
+            memory_kib: self.passwords.memory_kib,
+            iterations: self.passwords.iterations,
+            parallelism: self.passwords.parallelism,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_name_any_setting() {
+        let timeout = Override::from_env("LAB2_LOGIN_LIMITS_LOCKOUT_SECS", "60").unwrap();
+        assert_eq!((timeout.section.as_str(), timeout.key.as_str(), timeout.value), ("login_limits", "lockout_secs", toml::Value::Integer(60)));
+        let bind = Override::from_env("LAB2_SERVER_BIND", "0.0.0.0:2017").unwrap();
+        assert_eq!(bind.value, toml::Value::String("0.0.0.0:2017".to_string()));
+        assert_eq!(Override::from_env("LAB2_BIND", "0.0.0.0:2017"), None);
+        assert_eq!(Override::from_env("LAB2_CONFIG", "lab2.toml"), None);
+
+        let secure = Override::from_arg("sessions.secure_cookie=true").unwrap();
+        assert_eq!((secure.section.as_str(), secure.key.as_str(), secure.value), ("sessions", "secure_cookie", toml::Value::Boolean(true)));
+        assert!(matches!(Override::from_arg("secure_cookie=true"), Err(Error::Invalid(_))));
+        assert!(matches!(Override::from_arg("cookies.secure=true"), Err(Error::Invalid(_))));
+    }
+
+    #[test]
+    fn file_paths_are_relative_to_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lab2.toml");
+        std::fs::write(&file, "[server]\nstatic_root = \"data\"\n[database]\npath = \"lab2.sqlitedb\"\npool_size = 2\n").unwrap();
+
+        let config = Config::from_sources(Some(&file), &[]).unwrap();
+        assert_eq!(config.server.static_root, dir.path().join("data"));
+        assert_eq!(config.database.path, dir.path().join("lab2.sqlitedb"));
+
+        let overrides = [
+            Override::from_arg("database.path=other.sqlitedb").unwrap(),
+            Override::from_env("LAB2_DATABASE_POOL_SIZE", "4").unwrap(),
+        ];
+        let config = Config::from_sources(Some(&file), &overrides).unwrap();
+        assert_eq!(config.database.path, Path::new("other.sqlitedb"));
+        assert_eq!(config.database.pool_size, 4);
+        assert_eq!(config.server.static_root, dir.path().join("data"));
+
+        let unknown = [Override::from_arg("database.pool=4").unwrap()];
+        assert!(matches!(Config::from_sources(Some(&file), &unknown), Err(Error::Override(_))));
+    }
+}
@@ -0,0 +1,166 @@
+//! Pooled SQLite access.
+//!
+//! The database runs in WAL mode so readers never wait for the writer.
+//! Reads go to a pool of read-only connections and run concurrently; all
+//! writes go through a single-connection pool, because SQLite allows one
+//! writer at a time anyway and queueing in the pool is cheaper than
+//! retrying on `SQLITE_BUSY`. Both run on tokio's blocking thread pool via
+//! `spawn_blocking`, never on the async executor.
+
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{Connection, OpenFlags};
+
+use crate::config::DatabaseConfig;
+use crate::migrations;
+
+#[derive(Debug)]
+pub enum Error {
+    Sqlite(rusqlite::Error),
+    /// No connection became free within the busy timeout.
+    Pool(r2d2::Error),
+    Migration(migrations::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Sqlite(err) => write!(f, "{err}"),
+            Error::Pool(err) => write!(f, "connection pool: {err}"),
+            Error::Migration(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Error {
+        Error::Sqlite(err)
+    }
+}
+
+impl From<r2d2::Error> for Error {
+    fn from(err: r2d2::Error) -> Error {
+        Error::Pool(err)
+    }
+}
+
+impl From<migrations::Error> for Error {
+    fn from(err: migrations::Error) -> Error {
+        Error::Migration(err)
+    }
+}
+
+#[derive(Clone)]
+pub struct Database {
+    readers: Pool<SqliteConnectionManager>,
+    writer: Pool<SqliteConnectionManager>,
+}
+
+impl Database {
+    /// Opens the database, switches it to WAL and applies pending migrations.
+    pub fn open(config: &DatabaseConfig) -> Result<Database, Error> {
+        let busy_timeout = Duration::from_millis(config.busy_timeout_ms);
+
+        let writer = Pool::builder()
+            .max_size(1)
+            .connection_timeout(busy_timeout)
+            .build(manager(&config.path, OpenFlags::default(), busy_timeout))?;
+        {
+            let mut conn = writer.get()?;
+            conn.pragma_update(None, "journal_mode", "wal")?;
+            migrations::run(&mut conn)?;
+        }
+
+        let readers = Pool::builder()
+            .max_size(config.pool_size)
+            .connection_timeout(busy_timeout)
+            .build(manager(&config.path, read_only_flags(), busy_timeout))?;
+
+        Ok(Database { readers, writer })
+    }
+
+    /// Runs `f` on a read-only connection.
+    pub async fn read<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T, rusqlite::Error> + Send + 'static,
+    {
+        let pool = self.readers.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = pool.get()?;
+            Ok(f(&conn)?)
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Runs `f` on the writer connection. Use `Connection::transaction` in
+    /// `f` when several statements must be applied together.
+    pub async fn write<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, rusqlite::Error> + Send + 'static,
+    {
+        let pool = self.writer.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = pool.get()?;
+            Ok(f(&mut conn)?)
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Blocking access to the writer, for startup code outside the runtime's
+    /// request handling.
+    pub fn writer(&self) -> Result<PooledConnection<SqliteConnectionManager>, Error> {
+        Ok(self.writer.get()?)
+    }
+}
+
+fn manager(path: &Path, flags: OpenFlags, busy_timeout: Duration) -> SqliteConnectionManager {
+    SqliteConnectionManager::file(path)
+        .with_flags(flags)
+        .with_init(move |conn| {
+            conn.busy_timeout(busy_timeout)?;
+            // SQLite enforces `references` only when asked, per connection.
+            conn.pragma_update(None, "foreign_keys", true)?;
+            // Safe with WAL: a crash may lose the last commits but never
+            // corrupts the database.
+            conn.pragma_update(None, "synchronous", "normal")
+        })
+}
+
+fn read_only_flags() -> OpenFlags {
+    OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX
+}
+
+#[cfg(test)]
+mod tests {
+    use r2d2::ManageConnection;
+
+    use super::*;
+
+    #[test]
+    fn every_connection_enforces_foreign_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = DatabaseConfig { path: dir.path().join("test.sqlitedb"), ..DatabaseConfig::default() };
+        let db = Database::open(&config).unwrap();
+
+        let reader = db.readers.get().unwrap();
+        let enforced: bool = reader.query_row("pragma foreign_keys;", [], |row| row.get(0)).unwrap();
+        assert!(enforced);
+
+        // A connection the writer pool opens later, e.g. after `max_lifetime`.
+        let conn = manager(&config.path, OpenFlags::default(), Duration::from_millis(config.busy_timeout_ms)).connect().unwrap();
+        conn.execute("insert into sessions(hash, is_auth, name) values ('hash', false, 'anon');", []).unwrap();
+        let dangling = conn.execute("insert into calculations(result, session_id, user_id) values (1, 1, 42);", []);
+        assert!(dangling.is_err());
+        conn.execute("insert into calculations(result, session_id, user_id) values (1, 1, null);", []).unwrap();
+    }
+}
@@ -0,0 +1,214 @@
+//! Errors returned by `/api` handlers.
+//!
+//! Every failed API request gets a JSON body of the form
+//!
+//! ```json
+//! { "error": { "code": "not_logged_in", "message": "...", "request_id": "5f0c1d2e3a4b6978" } }
+//! ```
+//!
+//! `code` is stable and meant for the front end, `message` is for humans.
+//! `request_id` is also sent as the `x-request-id` header and printed to the
+//! server log next to the underlying cause, so a report can be matched to it.
+//!
+//! | status | code                  | when                                             |
+//! |--------|-----------------------|--------------------------------------------------|
+//! | 400    | `bad_request`         | malformed body, header or expression             |
+//! | 401    | `not_logged_in`       | the endpoint needs a logged-in session           |
+//! | 401    | `invalid_credentials` | login with unknown name or wrong password        |
+//! | 401    | `session_not_found`   | the session cookie is missing or expired         |
+//! | 403    | `forbidden`           | the user's role lacks the required permission    |
+//! | 404    | `not_found`           | unknown endpoint or record                       |
+//! | 405    | `method_not_allowed`  | known endpoint, wrong HTTP method                |
+//! | 409    | `conflict`            | the record already exists                        |
+//! | 413    | `payload_too_large`   | body over the size limit                         |
+//! | 422    | `division_by_zero`    | a calculation divides by zero                    |
+//! | 422    | `overflow`            | a result too large for its numeric mode          |
+//! | 422    | `not_a_number`        | an `f64` result is NaN                           |
+//! | 429    | `too_many_requests`   | too many failed logins; see `throttle`           |
+//! | 500    | `internal`            | database or other server failure                 |
+//!
+//! The 422 errors are not raised when the request opts into IEEE results;
+//! see `numeric`. A 429 also carries `Retry-After` in seconds.
+
+use std::convert::Infallible;
+use std::fmt;
+use std::time::Duration;
+
+use serde_derive::Serialize;
+use warp::http::StatusCode;
+use warp::reply::{Reply, Response};
+
+use calc_core::numeric;
+
+use crate::db;
+use crate::models::Permission;
+
+#[derive(Debug)]
+pub enum Error {
+    BadRequest(String),
+    NotLoggedIn,
+    InvalidCredentials,
+    SessionNotFound,
+    Forbidden(Permission),
+    NotFound(String),
+    MethodNotAllowed,
+    Conflict(String),
+    PayloadTooLarge,
+    Arithmetic(numeric::Error),
+    /// How long the client has to wait.
+    TooManyRequests(Duration),
+    Database(db::Error),
+    Internal(String),
+}
+
+impl warp::reject::Reject for Error {}
+
+impl From<db::Error> for Error {
+    fn from(err: db::Error) -> Error {
+        Error::Database(err)
+    }
+}
+
+impl From<numeric::Error> for Error {
+    fn from(err: numeric::Error) -> Error {
+        if err.is_arithmetic() {
+            Error::Arithmetic(err)
+        } else {
+            Error::BadRequest(err.to_string())
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Error {
+        Error::Database(db::Error::Sqlite(err))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::BadRequest(message) => write!(f, "{message}"),
+            Error::NotLoggedIn => write!(f, "you need to log in first"),
+            Error::InvalidCredentials => write!(f, "wrong name or password"),
+            Error::SessionNotFound => write!(f, "session not found or expired"),
+            Error::Forbidden(permission) => write!(f, "missing permission '{}'", permission.as_str()),
+            Error::NotFound(what) => write!(f, "{what} not found"),
+            Error::MethodNotAllowed => write!(f, "method not allowed"),
+            Error::Conflict(message) => write!(f, "{message}"),
+            Error::PayloadTooLarge => write!(f, "payload too large"),
+            Error::Arithmetic(err) => write!(f, "{err}"),
+            Error::TooManyRequests(wait) => write!(f, "too many failed attempts, try again in {}s", retry_after_secs(*wait)),
+            // Internal details go to the log, not to the client.
+            Error::Database(_) | Error::Internal(_) => write!(f, "internal server error"),
+        }
+    }
+}
+
+impl Error {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Error::NotLoggedIn | Error::InvalidCredentials | Error::SessionNotFound => StatusCode::UNAUTHORIZED,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            Error::Conflict(_) => StatusCode::CONFLICT,
+            Error::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::Arithmetic(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            Error::Database(_) | Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::BadRequest(_) => "bad_request",
+            Error::NotLoggedIn => "not_logged_in",
+            Error::InvalidCredentials => "invalid_credentials",
+            Error::SessionNotFound => "session_not_found",
+            Error::Forbidden(_) => "forbidden",
+            Error::NotFound(_) => "not_found",
+            Error::MethodNotAllowed => "method_not_allowed",
+            Error::Conflict(_) => "conflict",
+            Error::PayloadTooLarge => "payload_too_large",
+            Error::Arithmetic(err) => err.code(),
+            Error::TooManyRequests(_) => "too_many_requests",
+            Error::Database(_) | Error::Internal(_) => "internal",
+        }
+    }
+
+    /// Builds the JSON error response and logs the cause under a fresh request id.
+    pub fn to_response(&self) -> Response {
+        let request_id = format!("{:016x}", rand::random::<u64>());
+        let cause = match self {
+            Error::Database(err) => format!("database error: {err}"),
+            Error::Internal(cause) => cause.clone(),
+            _ => self.to_string(),
+        };
+        if self.status().is_server_error() {
+            log::error!("request {request_id} failed with {}: {cause}", self.status());
+        } else {
+            log::info!("request {request_id} failed with {}: {cause}", self.status());
+        }
+
+        let body = ErrorEnvelope {
+            error: ErrorBody {
+                code: self.code(),
+                message: self.to_string(),
+                request_id: &request_id,
+            },
+        };
+        let reply = warp::reply::with_status(warp::reply::json(&body), self.status());
+        let mut response = warp::reply::with_header(reply, "x-request-id", request_id).into_response();
+        if let Error::TooManyRequests(wait) = self {
+            response.headers_mut().insert("retry-after", retry_after_secs(*wait).into());
+        }
+        response
+    }
+}
+
+/// Whole seconds, rounded up so a client that waits this long is let in.
+fn retry_after_secs(wait: Duration) -> u64 {
+    wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+}
+
+#[derive(Serialize)]
+struct ErrorEnvelope<'a> {
+    error: ErrorBody<'a>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'static str,
+    message: String,
+    request_id: &'a str,
+}
+
+/// `recover` handler for `/api`: turns every rejection into the JSON envelope.
+pub async fn handle_rejection(err: warp::Rejection) -> Result<Response, Infallible> {
+    let error = if let Some(error) = err.find::<Error>() {
+        return Ok(error.to_response());
+    } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        Error::BadRequest(e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::MissingHeader>() {
+        Error::BadRequest(e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::InvalidHeader>() {
+        Error::BadRequest(e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::UnsupportedMediaType>() {
+        Error::BadRequest(e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+        Error::BadRequest(e.to_string())
+    } else if err.find::<warp::reject::MissingCookie>().is_some() {
+        Error::SessionNotFound
+    } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        Error::PayloadTooLarge
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        Error::MethodNotAllowed
+    } else if err.is_not_found() {
+        Error::NotFound("endpoint".to_string())
+    } else {
+        Error::Internal(format!("{err:?}"))
+    };
+    Ok(error.to_response())
+}
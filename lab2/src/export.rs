@@ -0,0 +1,172 @@
+//! Formats for `GET /api/history/export?format=csv|jsonl|tsv`.
+//!
+//! Every format has the same fields, oldest calculation first:
+//! `id, created_at, expression, num1, operator, num2, result, mode`.
+//! `created_at` is UTC in ISO 8601 and empty for calculations made before
+//! it was recorded; `operator` is the name from the `operators` table.
+//! Numbers are written the same way whatever the client's locale: `.` as
+//! the decimal separator and no digit grouping; `inf`, `-inf` and `NaN`
+//! are written as those words. `result` is exact in the
+//! `decimal` and `rational` modes, so it may be a fraction such as `1/3`.
+
+use std::io;
+
+use serde_derive::{Deserialize, Serialize};
+
+use calc_core::numeric::{self, JsonNumber, Mode};
+
+use crate::models::Calculation;
+use crate::operators::Registry;
+
+const COLUMNS: [&str; 8] = ["id", "created_at", "expression", "num1", "operator", "num2", "result", "mode"];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Jsonl,
+    /// Tab-separated, pastes straight into a spreadsheet.
+    Tsv,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Jsonl => "application/x-ndjson",
+            ExportFormat::Tsv => "text/tab-separated-values; charset=utf-8",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Jsonl => "jsonl",
+            ExportFormat::Tsv => "tsv",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportRow<'a> {
+    pub id: i32,
+    pub created_at: String,
+    pub expression: String,
+    #[serde(serialize_with = "numeric::serialize_option_f64")]
+    pub num1: Option<f64>,
+    pub operator: Option<&'a str>,
+    #[serde(serialize_with = "numeric::serialize_option_f64")]
+    pub num2: Option<f64>,
+    pub result: JsonNumber,
+    pub mode: Mode,
+}
+
+impl<'a> ExportRow<'a> {
+    pub fn new(calculation: &Calculation, registry: &'a Registry) -> ExportRow<'a> {
+        let operator = calculation.operator_id.and_then(|id| registry.get(id));
+        let expression = match (&calculation.expression, calculation.num1, operator, calculation.num2) {
+            (Some(expression), ..) => expression.clone(),
+            // Rows from before expressions were stored.
+            (None, Some(num1), Some(operator), Some(num2)) => format!("{num1} {} {num2}", operator.symbol),
+            _ => String::new(),
+        };
+        ExportRow {
+            id: calculation.id,
+            created_at: if calculation.created_at > 0 { format_utc(calculation.created_at) } else { String::new() },
+            expression,
+            num1: calculation.num1,
+            operator: operator.map(|operator| operator.name.as_str()),
+            num2: calculation.num2,
+            result: match &calculation.exact_result {
+                Some(text) => numeric::json_number(text),
+                None => JsonNumber::from(calculation.result),
+            },
+            mode: calculation.mode,
+        }
+    }
+}
+
+/// Turns batches of rows into the bytes of one export file. The first
+/// batch starts with the header, if the format has one.
+pub struct Encoder {
+    /// `None` for JSON Lines.
+    delimiter: Option<u8>,
+    header_written: bool,
+}
+
+impl Encoder {
+    pub fn new(format: ExportFormat) -> Encoder {
+        let delimiter = match format {
+            ExportFormat::Csv => Some(b','),
+            ExportFormat::Tsv => Some(b'\t'),
+            ExportFormat::Jsonl => None,
+        };
+        Encoder { delimiter, header_written: false }
+    }
+
+    pub fn encode(&mut self, rows: &[ExportRow]) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        match self.delimiter {
+            Some(delimiter) => {
+                let mut writer = csv::WriterBuilder::new().delimiter(delimiter).has_headers(false).from_writer(&mut buf);
+                if !self.header_written {
+                    writer.write_record(COLUMNS)?;
+                    self.header_written = true;
+                }
+                for row in rows {
+                    writer.serialize(row)?;
+                }
+                writer.flush()?;
+            },
+            None => {
+                for row in rows {
+                    serde_json::to_writer(&mut buf, row)?;
+                    buf.push(b'\n');
+                }
+            },
+        }
+        Ok(buf)
+    }
+}
+
+/// `attachment` filename for an export made at `now` (unix seconds).
+pub fn filename(format: ExportFormat, now: i64) -> String {
+    format!("history-{}.{}", &format_utc(now)[..10], format.extension())
+}
+
+/// Unix seconds as `YYYY-MM-DDTHH:MM:SSZ`.
+pub fn format_utc(secs: i64) -> String {
+    let (days, secs) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    // Days to a proleptic Gregorian date, from Howard Hinnant's `civil_from_days`.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_utc_dates() {
+        assert_eq!(format_utc(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_utc(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_utc(1_792_317_900), "2026-10-18T10:05:00Z");
+        assert_eq!(filename(ExportFormat::Tsv, 1_792_317_900), "history-2026-10-18.tsv");
+    }
+}
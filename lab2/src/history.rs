@@ -0,0 +1,224 @@
+//! Query parameters, filtering and cursors for `GET /api/history`.
+//!
+//! Pages are keyset-paginated on `(sort key, id)`, so a page stays stable
+//! while new calculations are added. Every parameter is optional:
+//!
+//! | parameter     | meaning                                               |
+//! |---------------|-------------------------------------------------------|
+//! | `limit`       | rows per page, 1 to 200, default 50                   |
+//! | `cursor`      | `next_cursor` of the previous page                    |
+//! | `sort`        | `created_at` (default) or `result`                    |
+//! | `order`       | `desc` (default) or `asc`                             |
+//! | `operator_id` | only calculations made with this operator             |
+//! | `from`, `to`  | unix seconds, `from <= created_at < to`               |
+//! | `min_result`, `max_result` | `min_result <= result <= max_result`     |
+//!
+//! A cursor is only valid with the `sort` and `order` it was issued for.
+
+#[cfg(test)]
+use std::cmp::Ordering;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine as _;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::models::Calculation;
+
+pub const DEFAULT_LIMIT: u32 = 50;
+pub const MAX_LIMIT: u32 = 200;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    #[default]
+    CreatedAt,
+    Result,
+}
+
+impl SortKey {
+    pub fn column(self) -> &'static str {
+        match self {
+            SortKey::CreatedAt => "created_at",
+            SortKey::Result => "result",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Order {
+    Asc,
+    #[default]
+    Desc,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HistoryQuery {
+    pub limit: Option<u32>,
+    pub cursor: Option<String>,
+    #[serde(default)]
+    pub sort: SortKey,
+    #[serde(default)]
+    pub order: Order,
+    pub operator_id: Option<i32>,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub min_result: Option<f64>,
+    pub max_result: Option<f64>,
+}
+
+/// The sort key value and id of the last row of the previous page.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cursor {
+    CreatedAt { created_at: i64, id: i32 },
+    Result { result: f64, id: i32 },
+}
+
+/// A validated `HistoryQuery`.
+#[derive(Debug, Clone)]
+pub struct HistoryFilter {
+    pub limit: u32,
+    pub after: Option<Cursor>,
+    pub sort: SortKey,
+    pub order: Order,
+    pub operator_id: Option<i32>,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub min_result: Option<f64>,
+    pub max_result: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Page {
+    pub history: Vec<Calculation>,
+    /// Pass as `cursor` to get the next page; `null` on the last page.
+    pub next_cursor: Option<String>,
+}
+
+impl TryFrom<HistoryQuery> for HistoryFilter {
+    type Error = String;
+
+    fn try_from(query: HistoryQuery) -> Result<HistoryFilter, String> {
+        let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+        if !(1..=MAX_LIMIT).contains(&limit) {
+            return Err(format!("limit must be between 1 and {MAX_LIMIT}"));
+        }
+        if let (Some(from), Some(to)) = (query.from, query.to) {
+            if from > to {
+                return Err("from must not be after to".to_string());
+            }
+        }
+        if query.min_result.is_some_and(f64::is_nan) || query.max_result.is_some_and(f64::is_nan) {
+            return Err("min_result and max_result must be numbers".to_string());
+        }
+        if let (Some(min), Some(max)) = (query.min_result, query.max_result) {
+            if min > max {
+                return Err("min_result must not exceed max_result".to_string());
+            }
+        }
+        let after = query.cursor
+            .map(|cursor| decode_cursor(&cursor, query.sort, query.order).ok_or("invalid cursor for this sort and order"))
+            .transpose()?;
+
+        Ok(HistoryFilter {
+            limit,
+            after,
+            sort: query.sort,
+            order: query.order,
+            operator_id: query.operator_id,
+            from: query.from,
+            to: query.to,
+            min_result: query.min_result,
+            max_result: query.max_result,
+        })
+    }
+}
+
+fn encode_cursor(sort: SortKey, order: Order, cursor: Cursor) -> String {
+    let order = order_name(order);
+    let text = match cursor {
+        Cursor::CreatedAt { created_at, id } => format!("{}:{order}:{created_at}:{id}", sort.column()),
+        Cursor::Result { result, id } => format!("{}:{order}:{result}:{id}", sort.column()),
+    };
+    URL_SAFE_NO_PAD.encode(text)
+}
+
+fn decode_cursor(cursor: &str, sort: SortKey, order: Order) -> Option<Cursor> {
+    let text = String::from_utf8(URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()?;
+    let mut parts = text.split(':');
+    let (column, cursor_order, key, id) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() || column != sort.column() || order_name(order) != cursor_order {
+        return None;
+    }
+    let id = id.parse().ok()?;
+    match sort {
+        SortKey::CreatedAt => Some(Cursor::CreatedAt { created_at: key.parse().ok()?, id }),
+        SortKey::Result => Some(Cursor::Result { result: key.parse().ok()?, id }),
+    }
+}
+
+fn order_name(order: Order) -> &'static str {
+    match order {
+        Order::Asc => "asc",
+        Order::Desc => "desc",
+    }
+}
+
+impl HistoryFilter {
+    // `matches`, `compare` and `is_after_cursor` mirror the SQL in
+    // `repository::sqlite` for the in-memory store.
+
+    /// Whether `calculation` passes the filters, ignoring the cursor.
+    #[cfg(test)]
+    pub fn matches(&self, calculation: &Calculation) -> bool {
+        self.operator_id.is_none_or(|id| calculation.operator_id == Some(id))
+            && self.from.is_none_or(|from| calculation.created_at >= from)
+            && self.to.is_none_or(|to| calculation.created_at < to)
+            && self.min_result.is_none_or(|min| calculation.result >= min)
+            && self.max_result.is_none_or(|max| calculation.result <= max)
+    }
+
+    /// Orders calculations the way pages list them.
+    #[cfg(test)]
+    pub fn compare(&self, a: &Calculation, b: &Calculation) -> Ordering {
+        let ordering = match self.sort {
+            SortKey::CreatedAt => a.created_at.cmp(&b.created_at),
+            SortKey::Result => a.result.total_cmp(&b.result),
+        }
+        .then(a.id.cmp(&b.id));
+        match self.order {
+            Order::Asc => ordering,
+            Order::Desc => ordering.reverse(),
+        }
+    }
+
+    /// Whether `calculation` comes after the cursor, i.e. belongs to a later page.
+    #[cfg(test)]
+    pub fn is_after_cursor(&self, calculation: &Calculation) -> bool {
+        let ordering = match self.after {
+            None => return true,
+            Some(Cursor::CreatedAt { created_at, id }) => (calculation.created_at, calculation.id).cmp(&(created_at, id)),
+            Some(Cursor::Result { result, id }) => calculation.result.total_cmp(&result).then(calculation.id.cmp(&id)),
+        };
+        match self.order {
+            Order::Asc => ordering == Ordering::Greater,
+            Order::Desc => ordering == Ordering::Less,
+        }
+    }
+
+    /// Builds a page from up to `limit + 1` sorted rows; the extra row only
+    /// signals that there is a next page.
+    pub fn page(&self, mut rows: Vec<Calculation>) -> Page {
+        let has_more = rows.len() > self.limit as usize;
+        rows.truncate(self.limit as usize);
+        let next_cursor = match rows.last() {
+            Some(last) if has_more => Some(encode_cursor(self.sort, self.order, match self.sort {
+                SortKey::CreatedAt => Cursor::CreatedAt { created_at: last.created_at, id: last.id },
+                SortKey::Result => Cursor::Result { result: last.result, id: last.id },
+            })),
+            _ => None,
+        };
+        Page { history: rows, next_cursor }
+    }
+}
@@ -0,0 +1,195 @@
+//! Parsing and validation for `POST /api/import_users`.
+//!
+//! Accepts the JSON produced by `/api/export_users?include_hashes=true`
+//! (`{"users": [...]}`) or a
+//! CSV file with the header `name,auth_hash,role`. Ids are never imported:
+//! an `id` field or column, as in the export, is ignored and every new user
+//! gets a fresh one.
+//! Only Argon2 PHC hashes are accepted in `auth_hash`, so imported accounts
+//! never carry plaintext passwords.
+
+use std::collections::HashSet;
+
+use argon2::password_hash::PasswordHash;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+/// What to do when an imported name already exists in the database.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictStrategy {
+    /// Keep the existing user and move on.
+    Skip,
+    /// Replace the existing user's hash and role.
+    Overwrite,
+    /// Abort the whole import.
+    #[default]
+    Fail,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ImportQuery {
+    #[serde(default)]
+    pub format: ImportFormat,
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub on_conflict: ConflictStrategy,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ImportUser {
+    pub name: String,
+    pub auth_hash: String,
+    pub role: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImportUsersJson {
+    users: Vec<ImportUser>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RowStatus {
+    Created,
+    Updated,
+    Skipped,
+    Conflict,
+    Invalid,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RowReport {
+    /// 1-based position in the uploaded file, not counting the CSV header.
+    pub row: usize,
+    pub name: String,
+    pub status: RowStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    /// Whether the changes were committed. Always false for a dry run.
+    pub applied: bool,
+    pub created: usize,
+    pub updated: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub rows: Vec<RowReport>,
+}
+
+impl ImportReport {
+    pub fn push(&mut self, row: usize, name: &str, status: RowStatus, message: Option<String>) {
+        match status {
+            RowStatus::Created => self.created += 1,
+            RowStatus::Updated => self.updated += 1,
+            RowStatus::Skipped => self.skipped += 1,
+            RowStatus::Conflict | RowStatus::Invalid => self.failed += 1,
+        }
+        self.rows.push(RowReport { row, name: name.to_owned(), status, message });
+    }
+
+    /// Whether the planned changes should be committed.
+    pub fn should_apply(&self) -> bool {
+        self.failed == 0 && !self.dry_run
+    }
+}
+
+pub fn parse(format: ImportFormat, body: &[u8]) -> Result<Vec<ImportUser>, Error> {
+    match format {
+        ImportFormat::Json => serde_json::from_slice::<ImportUsersJson>(body)
+            .map(|json| json.users)
+            .map_err(|err| Error::BadRequest(format!("invalid JSON import: {err}"))),
+        ImportFormat::Csv => csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(body)
+            .deserialize()
+            .collect::<Result<Vec<ImportUser>, _>>()
+            .map_err(|err| Error::BadRequest(format!("invalid CSV import: {err}"))),
+    }
+}
+
+/// Checks one record on its own and against the records before it.
+fn validate(user: &ImportUser, roles: &HashSet<String>, seen_names: &mut HashSet<String>) -> Result<(), String> {
+    if user.name.trim().is_empty() {
+        return Err("name is empty".to_string());
+    }
+    if user.name.chars().count() > MAX_NAME_LEN {
+        return Err(format!("name is longer than {MAX_NAME_LEN} characters"));
+    }
+    match PasswordHash::new(&user.auth_hash) {
+        Ok(hash) if hash.algorithm.as_str().starts_with("argon2") => {},
+        _ => return Err("auth_hash is not an Argon2 PHC hash".to_string()),
+    }
+    if !roles.contains(&user.role) {
+        return Err(format!("unknown role '{}'", user.role));
+    }
+    if !seen_names.insert(user.name.clone()) {
+        return Err(format!("name '{}' appears more than once", user.name));
+    }
+    Ok(())
+}
+
+/// A change an import wants to make once every row checked out.
+#[derive(Debug, Clone, Copy)]
+pub enum Change<'a> {
+    Create(&'a ImportUser),
+    /// Overwrite the user with this id.
+    Update(i32, &'a ImportUser),
+}
+
+/// Decides what happens to each record. `existing` looks a name up in the
+/// store the import runs against and should see the same transaction the
+/// changes are later applied in. The changes are only meant to be applied
+/// when the report has no failures and this is not a dry run.
+pub fn plan<'a, E>(
+    records: &'a [ImportUser],
+    roles: &HashSet<String>,
+    query: &ImportQuery,
+    mut existing: impl FnMut(&str) -> Result<Option<i32>, E>,
+) -> Result<(ImportReport, Vec<Change<'a>>), E> {
+    let mut report = ImportReport { dry_run: query.dry_run, ..ImportReport::default() };
+    let mut changes = Vec::new();
+    let mut seen_names = HashSet::new();
+
+    for (i, record) in records.iter().enumerate() {
+        let row = i + 1;
+        if let Err(message) = validate(record, roles, &mut seen_names) {
+            report.push(row, &record.name, RowStatus::Invalid, Some(message));
+            continue;
+        }
+
+        match (existing(&record.name)?, query.on_conflict) {
+            (None, _) => {
+                changes.push(Change::Create(record));
+                report.push(row, &record.name, RowStatus::Created, None);
+            },
+            (Some(_), ConflictStrategy::Skip) => {
+                report.push(row, &record.name, RowStatus::Skipped, Some("user already exists".to_string()));
+            },
+            (Some(user_id), ConflictStrategy::Overwrite) => {
+                changes.push(Change::Update(user_id, record));
+                report.push(row, &record.name, RowStatus::Updated, None);
+            },
+            (Some(_), ConflictStrategy::Fail) => {
+                report.push(row, &record.name, RowStatus::Conflict, Some("user already exists".to_string()));
+            },
+        }
+    }
+
+    Ok((report, changes))
+}
@@ -1,59 +1,274 @@
+// The page and API filter chains nest deeper than the default limit allows
+// once optimised builds check their futures for `Unpin`.
+#![recursion_limit = "256"]
+
 use std::env;
+use std::sync::Arc;
 use warp::Filter;
 
+mod config;
+mod db;
+mod error;
+mod export;
+mod history;
+mod import;
+mod migrations;
+mod operators;
+mod password;
+mod repository;
+#[cfg(test)]
+mod tests;
+mod throttle;
+
 #[tokio::main]
 async fn main() {
     if env::var_os("RUST_LOG").is_none() {
-        // Set `RUST_LOG=site=debug` to see debug logs,
-        // this only shows access logs.
-        env::set_var("RUST_LOG", "site=info");
+        // Access logs (`site`) and what the server does (`lab2`); set
+        // `RUST_LOG=site=debug,lab2=debug` to see debug logs too.
+        env::set_var("RUST_LOG", "site=info,lab2=info");
     }
     pretty_env_logger::init();
     
-    let db = models::open_db("database.sqlitedb");
 
-    let api = filters::site(db);
+    let config = match config::Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(2);
+        }
+    };
+
+    let db = match db::Database::open(&config.database) {
+        Ok(db) => db,
+        Err(err) => {
+            eprintln!("cannot open database: {err}");
+            std::process::exit(1);
+        }
+    };
+    let names = match models::load_session_names(&config.server.static_root) {
+        Ok(names) => names,
+        Err(err) => {
+            eprintln!("cannot read names.txt: {err}");
+            std::process::exit(1);
+        }
+    };
+    let issuer = Arc::new(models::SessionIssuer {
+        names,
+        secure_cookie: config.sessions.secure_cookie,
+    });
+    // Cost parameters were checked by `Config::validate`.
+    let passwords = password::Passwords::new(config.hash_cost()).unwrap();
+    let registry = match db.writer() {
+        Ok(conn) => operators::load(&conn).map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+    let registry = match registry {
+        Ok(registry) => Arc::new(registry),
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+
+    let repos = repository::Repositories::sqlite(db);
+    let throttle = throttle::Throttle::new(config.throttle_policy());
+
+    tokio::spawn(handlers::purge_expired_task(repos.clone(), throttle.clone(), config.session_lifetimes(), config.undo_window()));
+
+    let api = filters::site(repos, issuer, registry, passwords, throttle, &config);
     let routes = api.with(warp::log("site"));
 
-    warp::serve(routes).run(([127, 0, 0, 1], 2017)).await;
-    // warp::serve(routes).run(([172, 17, 9, 223], 3030)).await;
+    warp::serve(routes).run(config.bind_addr().unwrap()).await;
 }
 
 mod models {
-    use rusqlite::Connection;
-    use std::sync::Arc;
-    use tokio::sync::Mutex;
+    use std::collections::HashSet;
+    use std::path::Path;
+    use std::str::FromStr;
+    use std::time::Duration;
     use serde_derive::{Deserialize, Serialize};
-    
-    pub type Database = Arc<Mutex<Connection>>;
+    use calc_core::expr::Expr;
+    use calc_core::numeric::{self, JsonNumber, Mode, Operand};
+    use crate::operators::Operator;
+
+    /// What `create_new_session` needs to hand out a session cookie.
+    #[derive(Debug)]
+    pub struct SessionIssuer {
+        /// Names used to build display names for anonymous sessions.
+        pub names: Vec<String>,
+        /// Adds `Secure` to the cookie; enable when served over HTTPS.
+        pub secure_cookie: bool,
+    }
 
     #[derive(Debug)]
     pub struct UnIdentified;
     impl warp::reject::Reject for UnIdentified {}
 
+    /// Actions that need more than a logged-in user. Which roles grant
+    /// them is stored in the `role_permissions` table.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+    #[serde(rename_all = "snake_case")]
+    #[allow(clippy::enum_variant_names)]
+    pub enum Permission {
+        ViewUsers,
+        DeleteUsers,
+        ExportUsers,
+        ImportUsers,
+    }
+
+    impl Permission {
+        pub fn as_str(self) -> &'static str {
+            match self {
+                Permission::ViewUsers => "view_users",
+                Permission::DeleteUsers => "delete_users",
+                Permission::ExportUsers => "export_users",
+                Permission::ImportUsers => "import_users",
+            }
+        }
+    }
+
+    impl FromStr for Permission {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Permission, String> {
+            match s {
+                "view_users" => Ok(Permission::ViewUsers),
+                "delete_users" => Ok(Permission::DeleteUsers),
+                "export_users" => Ok(Permission::ExportUsers),
+                "import_users" => Ok(Permission::ImportUsers),
+                _ => Err(format!("unknown permission '{s}'")),
+            }
+        }
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct Role {
+        pub name: String,
+        pub permissions: HashSet<Permission>,
+    }
+
+    impl Role {
+        pub fn allows(&self, permission: Permission) -> bool {
+            self.permissions.contains(&permission)
+        }
+    }
+
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct CalculateJson {
-        pub num1: f64,
-        pub num2: f64,
+        pub num1: Operand,
+        pub num2: Operand,
         pub operator_id: i32,
-        pub result: Option<f64>,
+        #[serde(default)]
+        pub mode: Mode,
+        /// Digits after the point in `decimal` mode; the server default if absent.
+        pub scale: Option<u32>,
+        /// Return `inf`, `-inf` and `NaN` instead of errors; see `numeric`.
+        #[serde(default)]
+        pub ieee: bool,
     }
 
-    #[derive(Debug, Deserialize, Serialize, Clone)]
-    pub struct HistoryJson {
-        pub history: Vec<Calculation>,
+    #[derive(Debug, Serialize, Clone)]
+    pub struct CalculateResultJson {
+        pub num1: Operand,
+        pub num2: Operand,
+        pub operator_id: i32,
+        pub mode: Mode,
+        pub result: JsonNumber,
     }
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct EvaluateJson {
+        pub expression: String,
+        #[serde(default)]
+        pub mode: Mode,
+        pub scale: Option<u32>,
+        #[serde(default)]
+        pub ieee: bool,
+    }
+
+    #[derive(Debug, Serialize, Clone)]
+    pub struct EvaluateResultJson {
+        pub expression: String,
+        pub mode: Mode,
+        pub result: JsonNumber,
+        pub ast: Expr,
+    }
+
+    #[derive(Debug, Serialize, Clone)]
+    pub struct OperatorsJson<'a> {
+        pub operators: Vec<&'a Operator>,
+    }
+
+    #[derive(Debug, Serialize, Clone)]
+    pub struct DeletedJson {
+        pub deleted: usize,
+        /// Unix seconds until which `POST /api/history/undo` brings it back.
+        pub undo_until: i64,
+    }
+
+    #[derive(Debug, Serialize, Clone)]
+    pub struct RestoredJson {
+        pub restored: usize,
+    }
+
+    #[derive(Debug, Serialize, Clone)]
+    pub struct RevokedJson {
+        pub revoked: usize,
+    }
+
+    /// A user as the user list shows them, without the password hash.
+    #[derive(Debug, Serialize, Clone)]
+    pub struct UserJson {
+        pub id: i32,
+        pub name: String,
+        pub role: String,
+    }
+
+    impl From<User> for UserJson {
+        fn from(user: User) -> UserJson {
+            UserJson { id: user.id, name: user.name, role: user.role }
+        }
+    }
+
+    #[derive(Debug, Serialize, Clone)]
     pub struct UsersJson {
+        pub users: Vec<UserJson>,
+    }
+
+    /// What `/api/export_users?include_hashes=true` sends: the users with
+    /// their password hashes, as `/api/import_users` takes them back.
+    #[derive(Debug, Serialize, Clone)]
+    pub struct UserDumpJson {
         pub users: Vec<User>,
     }
 
+    #[derive(Debug, Deserialize, Clone, Default)]
+    #[serde(deny_unknown_fields)]
+    pub struct ExportUsersQuery {
+        /// Hashes are only exported when asked for, to restore a backup.
+        #[serde(default)]
+        pub include_hashes: bool,
+    }
+
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct TestLoginJson {
         pub name: String,
         pub password: String,
+        /// Move calculations made before logging in into the account.
+        #[serde(default = "adopt_history_by_default")]
+        pub adopt_history: bool,
+    }
+
+    fn adopt_history_by_default() -> bool {
+        true
+    }
+
+    #[derive(Debug, Serialize, Clone)]
+    pub struct LoginResultJson {
+        pub user_id: i32,
+        /// Anonymous calculations that moved into the account.
+        pub adopted: usize,
     }
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -71,60 +286,131 @@ mod models {
         pub is_auth: bool,   
         pub user_id: Option<i32>,
         pub name: String,
+        pub created_at: i64,
+        pub last_seen_at: i64,
+        pub user_agent: Option<String>,
+        pub ip: Option<String>,
+    }
+
+    /// Where a request came from, recorded when a session logs in.
+    #[derive(Debug, Clone, Default)]
+    pub struct Client {
+        pub user_agent: Option<String>,
+        pub ip: Option<String>,
+    }
+
+    /// One of the user's live sessions, as `GET /api/sessions` lists it.
+    #[derive(Debug, Serialize, Clone)]
+    pub struct ActiveSessionJson {
+        pub id: i32,
+        pub created_at: i64,
+        pub last_seen_at: i64,
+        pub user_agent: Option<String>,
+        pub ip: Option<String>,
+        /// The session making the request.
+        pub current: bool,
+    }
+
+    #[derive(Debug, Serialize, Clone)]
+    pub struct SessionsJson {
+        pub sessions: Vec<ActiveSessionJson>,
+    }
+
+    /// How long sessions stay valid. A session expires `absolute` after it
+    /// was created, or after `idle` without requests, whichever comes first.
+    #[derive(Debug, Clone, Copy)]
+    pub struct SessionLifetimes {
+        pub absolute: Duration,
+        pub idle: Duration,
+        pub cleanup_interval: Duration,
+    }
+
+    impl Default for SessionLifetimes {
+        fn default() -> SessionLifetimes {
+            SessionLifetimes {
+                absolute: Duration::from_secs(30 * 24 * 60 * 60),
+                idle: Duration::from_secs(24 * 60 * 60),
+                cleanup_interval: Duration::from_secs(10 * 60),
+            }
+        }
     }
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct Calculation {
         pub id: i32,
-        pub num1: f64,
-        pub num2: f64,
-        pub operator_id: i32,
+        #[serde(serialize_with = "numeric::serialize_option_f64")]
+        pub num1: Option<f64>,
+        #[serde(serialize_with = "numeric::serialize_option_f64")]
+        pub num2: Option<f64>,
+        pub operator_id: Option<i32>,
+        /// `inf`, `-inf` and `NaN` are sent as strings.
+        #[serde(serialize_with = "numeric::serialize_f64")]
         pub result: f64,
-        pub session_id: i32,
+        /// `None` once the session it was made in has been purged.
+        pub session_id: Option<i32>,
         pub user_id: Option<i32>,
+        pub expression: Option<String>,
+        /// Unix seconds; 0 for calculations made before this was recorded.
+        pub created_at: i64,
+        pub mode: Mode,
+        /// The exact result in the `decimal` and `rational` modes.
+        pub exact_result: Option<String>,
     }
 
 
-    pub fn open_db(name_db: &str) -> Database {
-        let db = Connection::open(name_db).unwrap();
-        let db = Arc::new(Mutex::new(db));
-        db
+    pub fn load_session_names(static_root: &Path) -> std::io::Result<Vec<String>> {
+        let contents = std::fs::read_to_string(static_root.join("names.txt"))?;
+        let names: Vec<String> = contents
+            .lines()
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_owned)
+            .collect();
+        if names.is_empty() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "names.txt is empty"));
+        }
+        Ok(names)
     }
+
 }
 
 mod filters {
     use std::convert::Infallible;
+    use std::net::SocketAddr;
 
-    use crate::{handlers, models};
-    use warp::http::StatusCode;
+    use crate::{error, handlers, models};
     use warp::{reply::Reply, Filter};
-    use crate::models::{CalculateJson, Database, TestLoginJson};
-
-    pub fn site(db: Database) -> impl Filter<Extract = (impl warp::Reply,), Error = Infallible> + Clone {
-        check_cookies()
-            .untuple_one()
-            .and(
-                api(db.clone())
-                .or(data())
-                .or(pages())
-                .or(wrong_door())
-            )
+    use std::path::{Path, PathBuf};
+
+    use crate::config::Config;
+    use crate::repository::Repositories;
+    use crate::models::{CalculateJson, Client, EvaluateJson, ExportUsersQuery, Permission, Session, SessionIssuer, SessionLifetimes, TestLoginJson, User};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::error::Error;
+    use calc_core::numeric::Limits;
+    use crate::export::ExportQuery;
+    use crate::history::HistoryQuery;
+    use crate::import::ImportQuery;
+    use crate::operators::Registry;
+    use crate::password::Passwords;
+    use crate::throttle::Throttle;
+
+    pub fn site(repos: Repositories, issuer: Arc<SessionIssuer>, registry: Arc<Registry>, passwords: Passwords, throttle: Throttle, config: &Config) -> impl Filter<Extract = (impl warp::Reply,), Error = Infallible> + Clone {
+        let server = &config.server;
+        // `/api` looks sessions up per endpoint and answers `session_not_found`
+        // itself, so only the pages check here: one lookup per request.
+        api(repos.clone(), issuer.clone(), registry, passwords, throttle, config)
+            .or(check_cookies(repos.clone(), config.session_lifetimes())
+                .untuple_one()
+                .and(
+                    data(server.static_root.clone())
+                    .or(pages(&server.static_root))
+                    .or(wrong_door())
+                ))
             .recover(  move |err|  {
-                handlers::user_have_not_cookies_situation(db.clone(), err)
+                handlers::user_have_not_cookies_situation(repos.clone(), issuer.clone(), err)
             } )
-            // .map(|_, r| r)
-
-        // warp::path("test")
-        //     .and(warp::path::param())
-        //     .and_then(|s: String| async {
-        //         if s == "abc" {
-        //             Ok(warp::path("end")
-        //                 .map(|| warp::reply()).boxed())
-        //         } else {
-        //             Ok(warp::path("noabc")
-        //                 .map(|| warp::reply()).boxed())
-        //         }
-        //     })
     }
 
     pub fn wrong_door() -> impl Filter<Extract = (impl warp::Reply,), Error = Infallible> + Clone {
@@ -133,617 +419,1153 @@ mod filters {
         })
     }
 
-    pub fn api(db: Database) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    pub fn api(repos: Repositories, issuer: Arc<SessionIssuer>, registry: Arc<Registry>, passwords: Passwords, throttle: Throttle, config: &Config) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        let (lifetimes, undo_window, limits) = (config.session_lifetimes(), config.undo_window(), config.numeric_limits());
+        let server = &config.server;
+        let body_limit = server.body_limit;
         warp::path("api").and(
-            calculate(db.clone())
-            .or(delete_cookies(db.clone()))
-            .or(login(db.clone()))
-            .or(logout(db.clone()))
-            .or(register(db.clone()))
-            .or(delete_history(db.clone()))
-            .or(export_users(db.clone()))
-            // .or(import_users(db.clone()))
-
-            .or(history(db.clone()))
-            .or(session_info(db.clone()))
-            .or(get_users(db.clone()))
-            .or(delete_user(db.clone()))
+            calculate(repos.clone(), registry.clone(), lifetimes, limits, body_limit)
+            .or(operators(registry.clone()))
+            .or(evaluate(repos.clone(), lifetimes, limits, body_limit))
+            .or(delete_cookies())
+            .or(login(repos.clone(), issuer.clone(), passwords.clone(), throttle.clone(), lifetimes, body_limit))
+            .or(logout(repos.clone(), issuer.clone()))
+            .or(logout_everywhere(repos.clone(), issuer.clone(), lifetimes))
+            .or(sessions(repos.clone(), lifetimes))
+            .or(revoke_session(repos.clone(), lifetimes))
+            .or(register(repos.clone(), issuer, passwords, throttle, lifetimes, body_limit))
+            .or(delete_history(repos.clone(), lifetimes, undo_window))
+            .or(delete_history_entry(repos.clone(), lifetimes, undo_window))
+            .or(undo_delete_history(repos.clone(), lifetimes, undo_window))
+            .or(export_users(repos.clone(), lifetimes))
+            .or(import_users(repos.clone(), lifetimes, server.import_body_limit))
+
+            .or(history(repos.clone(), lifetimes))
+            .or(export_history(repos.clone(), registry.clone(), lifetimes))
+            .or(session_info(repos.clone(), lifetimes))
+            .or(get_users(repos.clone(), lifetimes))
+            .or(delete_user(repos.clone(), lifetimes))
+            .recover(error::handle_rejection)
         )
     }
 
-    pub fn delete_user(db: Database) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    pub fn delete_user(repos: Repositories, lifetimes: SessionLifetimes) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
         warp::path("delete_user")
             .and(warp::path::end())
             .and(warp::post())
-            .and(warp::cookie("session_hash"))
+            .and(with_permission(repos.clone(), lifetimes, Permission::DeleteUsers))
             .and(warp::header("user_id"))
-            .and(with_db(db))
+            .and(with_repos(repos))
             .and_then(handlers::delete_user)
     }
 
-    pub fn get_users(db: Database) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    pub fn import_users(repos: Repositories, lifetimes: SessionLifetimes, body_limit: u64) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path("import_users")
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(with_permission(repos.clone(), lifetimes, Permission::ImportUsers))
+            .and(warp::query::<ImportQuery>())
+            .and(warp::body::content_length_limit(body_limit))
+            .and(warp::body::bytes())
+            .and(with_repos(repos))
+            .and_then(handlers::import_users)
+    }
+
+    pub fn get_users(repos: Repositories, lifetimes: SessionLifetimes) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
         warp::path("get_users")
             .and(warp::path::end())
             .and(warp::get())
-            .and(warp::cookie("session_hash"))
-            .and(with_db(db))
+            .and(with_permission(repos.clone(), lifetimes, Permission::ViewUsers))
+            .and(with_repos(repos))
             .and_then(handlers::get_users)
     }
 
-    pub fn calculate(db: Database) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    pub fn calculate(repos: Repositories, registry: Arc<Registry>, lifetimes: SessionLifetimes, limits: Limits, body_limit: u64) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
         warp::path("calculate")
             .and(warp::path::end())
             .and(warp::post())
-            .and(warp::cookie("session_hash"))
-            .and(json_body_calculate())
-            .and(with_db(db))
+            .and(with_session(repos.clone(), lifetimes))
+            .and(json_body_calculate(body_limit))
+            .and(with_registry(registry))
+            .and(with_repos(repos))
+            .and(with_limits(limits))
             .and_then(handlers::calculate)
     }
 
-    pub fn login(db: Database) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    pub fn operators(registry: Arc<Registry>) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path("operators")
+            .and(warp::path::end())
+            .and(warp::get())
+            .and(with_registry(registry))
+            .and_then(handlers::operators)
+    }
+
+    pub fn evaluate(repos: Repositories, lifetimes: SessionLifetimes, limits: Limits, body_limit: u64) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path("evaluate")
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(with_session(repos.clone(), lifetimes))
+            .and(json_body_evaluate(body_limit))
+            .and(with_repos(repos))
+            .and(with_limits(limits))
+            .and_then(handlers::evaluate)
+    }
+
+    pub fn login(repos: Repositories, issuer: Arc<SessionIssuer>, passwords: Passwords, throttle: Throttle, lifetimes: SessionLifetimes, body_limit: u64) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
         warp::path("login")
             .and(warp::path::end())
             .and(warp::post())
-            .and(warp::cookie("session_hash"))
-            .and(json_body_login())
-            .and(with_db(db))
+            .and(with_session(repos.clone(), lifetimes))
+            .and(json_body_login(body_limit))
+            .and(with_client())
+            .and(with_repos(repos))
+            .and(with_issuer(issuer))
+            .and(with_passwords(passwords))
+            .and(with_throttle(throttle))
             .and_then(handlers::login)
     }
 
-    pub fn register(db: Database) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    pub fn register(repos: Repositories, issuer: Arc<SessionIssuer>, passwords: Passwords, throttle: Throttle, lifetimes: SessionLifetimes, body_limit: u64) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
         warp::path("register")
             .and(warp::path::end())
             .and(warp::post())
-            .and(warp::cookie("session_hash"))
-            .and(json_body_login())
-            .and(with_db(db))
+            .and(with_session(repos.clone(), lifetimes))
+            .and(json_body_login(body_limit))
+            .and(with_client())
+            .and(with_repos(repos))
+            .and(with_issuer(issuer))
+            .and(with_passwords(passwords))
+            .and(with_throttle(throttle))
             .and_then(handlers::register)
     }
 
-    pub fn logout(db: Database) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    pub fn logout(repos: Repositories, issuer: Arc<SessionIssuer>) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
         warp::path("logout")
             .and(warp::path::end())
-            .and(warp::get())
+            .and(warp::post())
             .and(warp::cookie("session_hash"))
-            .and(with_db(db))
-            .and_then(handlers::create_new_session)
-            
-            // .map(|session_hash: String| {
-            //     warp::reply::with_header(
-            //         // warp::redirect(warp::http::Uri::from_static("/")),
-            //         "LOGOUTED",
-            //         "set-cookie",
-            //         format!("session_hash=deleted; path=/; expires=Thu, 01 Jan 1970 00:00:00 GMT")).into_response()
-            // })
-    }
-
-    pub fn session_info(db: Database) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+            .and(with_repos(repos))
+            .and(with_issuer(issuer))
+            .and_then(handlers::logout)
+    }
+
+    pub fn logout_everywhere(repos: Repositories, issuer: Arc<SessionIssuer>, lifetimes: SessionLifetimes) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path("logout_everywhere")
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(with_session(repos.clone(), lifetimes))
+            .and(with_repos(repos))
+            .and(with_issuer(issuer))
+            .and_then(handlers::logout_everywhere)
+    }
+
+    pub fn sessions(repos: Repositories, lifetimes: SessionLifetimes) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path("sessions")
+            .and(warp::path::end())
+            .and(warp::get())
+            .and(with_session(repos.clone(), lifetimes))
+            .and(with_repos(repos))
+            .and(with_lifetimes(lifetimes))
+            .and_then(handlers::sessions)
+    }
+
+    pub fn revoke_session(repos: Repositories, lifetimes: SessionLifetimes) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("sessions" / i32)
+            .and(warp::delete())
+            .and(with_session(repos.clone(), lifetimes))
+            .and(with_repos(repos))
+            .and_then(handlers::revoke_session)
+    }
+
+    pub fn session_info(repos: Repositories, lifetimes: SessionLifetimes) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
         warp::path("session_info")
             .and(warp::path::end())
             .and(warp::get())
-            .and(warp::cookie("session_hash"))
-            .and(with_db(db))
+            .and(with_session(repos, lifetimes))
             .and_then(handlers::session_info)
     }
 
-    pub fn history(db: Database) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    pub fn history(repos: Repositories, lifetimes: SessionLifetimes) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
         warp::path("history")
             .and(warp::path::end())
             .and(warp::get())
-            .and(warp::cookie("session_hash"))
-            .and(with_db(db))
+            .and(with_session(repos.clone(), lifetimes))
+            .and(warp::query::<HistoryQuery>())
+            .and(with_repos(repos))
             .and_then(handlers::history)
     }
 
-    pub fn delete_history(db: Database) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    pub fn delete_history(repos: Repositories, lifetimes: SessionLifetimes, undo_window: Duration) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
         warp::path("delete_history")
             .and(warp::path::end())
             .and(warp::post())
-            .and(warp::cookie("session_hash"))
-            .and(with_db(db))
+            .and(with_session(repos.clone(), lifetimes))
+            .and(with_repos(repos))
+            .and(with_undo_window(undo_window))
             .and_then(handlers::delete_history)
     }
 
-    pub fn export_users(db: Database) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    pub fn export_history(repos: Repositories, registry: Arc<Registry>, lifetimes: SessionLifetimes) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("history" / "export")
+            .and(warp::get())
+            .and(with_session(repos.clone(), lifetimes))
+            .and(warp::query::<ExportQuery>())
+            .and(with_repos(repos))
+            .and(with_registry(registry))
+            .and_then(handlers::export_history)
+    }
+
+    pub fn delete_history_entry(repos: Repositories, lifetimes: SessionLifetimes, undo_window: Duration) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("history" / i32)
+            .and(warp::delete())
+            .and(with_session(repos.clone(), lifetimes))
+            .and(with_repos(repos))
+            .and(with_undo_window(undo_window))
+            .and_then(handlers::delete_history_entry)
+    }
+
+    pub fn undo_delete_history(repos: Repositories, lifetimes: SessionLifetimes, undo_window: Duration) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("history" / "undo")
+            .and(warp::post())
+            .and(with_session(repos.clone(), lifetimes))
+            .and(with_repos(repos))
+            .and(with_undo_window(undo_window))
+            .and_then(handlers::undo_delete_history)
+    }
+
+    pub fn export_users(repos: Repositories, lifetimes: SessionLifetimes) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
         warp::path("export_users")
             .and(warp::path::end())
             .and(warp::get())
-            .and(warp::cookie("session_hash"))
-            .and(with_db(db))
+            .and(with_permission(repos.clone(), lifetimes, Permission::ExportUsers))
+            .and(warp::query::<ExportUsersQuery>())
+            .and(with_repos(repos))
             .and_then(handlers::export_users)
     }
 
-    pub fn delete_cookies(db: Database) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    pub fn delete_cookies() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
         warp::path("delete_cookies")
             .and(warp::path::end())
             .and(warp::get())
-            .and(with_db(db))
-            .map(|db| {
+            .map(|| {
                 warp::reply::with_header(
                     warp::reply(),
                     "set-cookie",
-                    format!("session_hash=deleted; path=/; expires=Thu, 01 Jan 1970 00:00:00 GMT")).into_response()
+                    "session_hash=deleted; path=/; expires=Thu, 01 Jan 1970 00:00:00 GMT").into_response()
             })
     }
 
-    pub fn data() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    pub fn data(static_root: PathBuf) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
         warp::path("data")
-            .and(warp::fs::dir("./data/"))
+            .and(warp::fs::dir(static_root))
     }
 
-    pub fn pages() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-        home_page()
-            .or(login_page())
-            .or(history_page())
-            .or(register_page())
-            .or(users_page())
+    pub fn pages(static_root: &Path) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        home_page(static_root)
+            .or(login_page(static_root))
+            .or(history_page(static_root))
+            .or(register_page(static_root))
+            .or(users_page(static_root))
     }
 
-    pub fn users_page() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    pub fn users_page(static_root: &Path) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
         warp::path("users")
             .and(warp::path::end())
             .and(warp::get())
-            .and(warp::fs::file("./data/users/users.html"))
+            .and(warp::fs::file(static_root.join("users/users.html")))
     }
 
-    pub fn home_page() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    pub fn home_page(static_root: &Path) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
         warp::path::end()
             .and(warp::get())
-            .and(warp::fs::file("./data/home/home.html"))
+            .and(warp::fs::file(static_root.join("home/home.html")))
     }
 
-    pub fn login_page() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    pub fn login_page(static_root: &Path) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
         warp::path("login")
             .and(warp::path::end())
             .and(warp::get())
-            .and(warp::fs::file("./data/login/login.html"))
+            .and(warp::fs::file(static_root.join("login/login.html")))
     }
 
-    pub fn register_page() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    pub fn register_page(static_root: &Path) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
         warp::path("register")
             .and(warp::path::end())
             .and(warp::get())
-            .and(warp::fs::file("./data/register/register.html"))
+            .and(warp::fs::file(static_root.join("register/register.html")))
     }
 
-    pub fn history_page() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    pub fn history_page(static_root: &Path) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
         warp::path("history")
             .and(warp::path::end())
             .and(warp::get())
-            .and(warp::fs::file("./data/history/history.html"))
+            .and(warp::fs::file(static_root.join("history/history.html")))
     }
 
-    fn with_db(db: Database) -> impl Filter<Extract = (Database,), Error = std::convert::Infallible> + Clone {
-        warp::any().map(move || db.clone())
+    fn with_repos(repos: Repositories) -> impl Filter<Extract = (Repositories,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || repos.clone())
     }
 
-    fn json_body_calculate() -> impl Filter<Extract = (CalculateJson,), Error = warp::Rejection> + Clone {
+    /// Resolves the logged-in user of the current session and checks that
+    /// their role grants `permission`.
+    fn with_permission(repos: Repositories, lifetimes: SessionLifetimes, permission: Permission) -> impl Filter<Extract = (User,), Error = warp::Rejection> + Clone {
+        with_session(repos.clone(), lifetimes)
+            .and_then(move |session_info: Session| {
+                let repos = repos.clone();
+                async move {
+                    handlers::authorize(repos, session_info, permission).await
+                }
+            })
+    }
+
+    /// The user agent and peer address of the request.
+    fn with_client() -> impl Filter<Extract = (Client,), Error = warp::Rejection> + Clone {
+        warp::header::optional::<String>("user-agent")
+            .and(warp::addr::remote())
+            .map(|user_agent, remote: Option<SocketAddr>| Client { user_agent, ip: remote.map(|addr| addr.ip().to_string()) })
+    }
+
+    fn with_issuer(issuer: Arc<SessionIssuer>) -> impl Filter<Extract = (Arc<SessionIssuer>,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || issuer.clone())
+    }
+
+    fn with_lifetimes(lifetimes: SessionLifetimes) -> impl Filter<Extract = (SessionLifetimes,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || lifetimes)
+    }
+
+    fn with_undo_window(undo_window: Duration) -> impl Filter<Extract = (Duration,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || undo_window)
+    }
+
+    fn with_limits(limits: Limits) -> impl Filter<Extract = (Limits,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || limits)
+    }
+
+    fn with_registry(registry: Arc<Registry>) -> impl Filter<Extract = (Arc<Registry>,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || registry.clone())
+    }
+
+    fn with_passwords(passwords: Passwords) -> impl Filter<Extract = (Passwords,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || passwords.clone())
+    }
+
+    fn with_throttle(throttle: Throttle) -> impl Filter<Extract = (Throttle,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || throttle.clone())
+    }
+
+    fn json_body_calculate(body_limit: u64) -> impl Filter<Extract = (CalculateJson,), Error = warp::Rejection> + Clone {
         // When accepting a body, we want a JSON body
         // (and to reject huge payloads)...
-        warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+        warp::body::content_length_limit(body_limit).and(warp::body::json())
     }
 
-    fn json_body_login() -> impl Filter<Extract = (TestLoginJson,), Error = warp::Rejection> + Clone {
+    fn json_body_evaluate(body_limit: u64) -> impl Filter<Extract = (EvaluateJson,), Error = warp::Rejection> + Clone {
         // When accepting a body, we want a JSON body
         // (and to reject huge payloads)...
-        warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+        warp::body::content_length_limit(body_limit).and(warp::body::json())
     }
 
-    fn check_cookies() -> impl Filter<Extract = ((), ), Error = warp::Rejection> + Clone {
+    fn json_body_login(body_limit: u64) -> impl Filter<Extract = (TestLoginJson,), Error = warp::Rejection> + Clone {
         // When accepting a body, we want a JSON body
         // (and to reject huge payloads)...
+        warp::body::content_length_limit(body_limit).and(warp::body::json())
+    }
+
+    fn check_cookies(repos: Repositories, lifetimes: SessionLifetimes) -> impl Filter<Extract = ((), ), Error = warp::Rejection> + Clone {
+        // Page requests without a live session are rejected here, and
+        // `site` recovers from that by issuing a new session cookie.
         warp::any()
             .and(warp::cookie::optional("session_hash"))
-            .and_then(|session_hash: Option<String>| async move {
-                println!("cookies take {session_hash:?}");
-                if session_hash == None{
-                    return Err(warp::reject::custom(models::UnIdentified));
+            .and_then(move |session_hash: Option<String>| {
+                let repos = repos.clone();
+                async move {
+                    let Some(session_hash) = session_hash else {
+                        return Err(warp::reject::custom(models::UnIdentified));
+                    };
+                    match handlers::get_session_info(repos, session_hash, lifetimes).await {
+                        Ok(Some(_)) => Ok(()),
+                        _ => Err(warp::reject::custom(models::UnIdentified)),
+                    }
                 }
-                Ok(())
             })
     }
 
-    // fn catch_header(db: Database) -> impl Filter<Extract = (_, ), Error = std::convert::Infallible> + Clone {
-    //     warp::header("session_hash")
-    // }
+    fn with_session(repos: Repositories, lifetimes: SessionLifetimes) -> impl Filter<Extract = (Session,), Error = warp::Rejection> + Clone {
+        warp::cookie::optional("session_hash")
+            .and_then(move |session_hash: Option<String>| {
+                let repos = repos.clone();
+                async move {
+                    let Some(session_hash) = session_hash else {
+                        return Err(warp::reject::custom(Error::SessionNotFound));
+                    };
+                    match handlers::get_session_info(repos, session_hash, lifetimes).await {
+                        Ok(Some(session_info)) => Ok(session_info),
+                        _ => Err(warp::reject::custom(Error::SessionNotFound)),
+                    }
+                }
+            })
+    }
 }
 
 mod handlers {
-    use crate::models::{CalculateJson, Calculation, Database, HistoryJson, Session, TestLoginJson, User, UsersJson};
-    use http::Error;
+    use calc_core::expr;
+    use calc_core::numeric::{self, Limits};
+    use crate::error::Error;
+    use crate::export::{self, ExportFormat, ExportQuery, ExportRow};
+    use crate::history::{self, HistoryFilter, HistoryQuery, Order};
+    use crate::import::{self, ImportQuery};
+    use crate::models::{ActiveSessionJson, CalculateJson, CalculateResultJson, DeletedJson, EvaluateJson, EvaluateResultJson, ExportUsersQuery, LoginResultJson, OperatorsJson, RestoredJson, RevokedJson, Permission, Session, SessionIssuer, SessionLifetimes, SessionsJson, TestLoginJson, User, UserDumpJson, UserJson, UsersJson, Client};
+    use crate::repository::{NewCalculation, Owner, Repositories};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use crate::operators::Registry;
+    use crate::password::{Passwords, Verification};
+    use crate::throttle::Throttle;
     use warp::reply::Reply;
-    use warp::http::StatusCode;
-    use rusqlite::{params, types::Null};
-    use rand;
-    use std::hash::{DefaultHasher, Hash, Hasher};
-    use base64::{engine::general_purpose::STANDARD, Engine as _};
-    use std::fs::File;
-    use std::io::prelude::*;
-    use base64::prelude::*;
-    use md5;
-
-    pub async fn calculate(session_hash: String, input_data: CalculateJson, db: Database) -> Result<impl warp::Reply, warp::Rejection> {
-        println!("hash123 - {}", session_hash.clone());
-        let mut result_data = CalculateJson {
+    use crate::db;
+    use warp::hyper::body::Bytes;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine as _;
+    use rand::rngs::OsRng;
+    use rand::RngCore;
+    use sha2::{Digest, Sha256};
+
+    pub async fn calculate(session_info: Session, input_data: CalculateJson, registry: Arc<Registry>, repos: Repositories, limits: Limits) -> Result<impl warp::Reply, warp::Rejection> {
+        let operator = registry.get(input_data.operator_id)
+            .ok_or_else(|| Error::BadRequest(format!("unknown operator_id {}", input_data.operator_id)))?;
+        let context = limits.context(input_data.mode, input_data.scale, input_data.ieee).map_err(Error::from)?;
+
+        let computed = (|| {
+            let (num1, num2) = (context.operand(&input_data.num1)?, context.operand(&input_data.num2)?);
+            let value = context.apply(operator.op, &num1, &num2)?;
+            Ok::<_, numeric::Error>((num1, num2, context.finish(value)))
+        })();
+        let (num1, num2, value) = computed.map_err(Error::from)?;
+        let (result, exact_result) = context.render(&value);
+
+        repos.calculations.insert(NewCalculation {
+            num1: Some(num1.to_f64()),
+            num2: Some(num2.to_f64()),
+            operator_id: Some(operator.id),
+            result: value.to_f64(),
+            session_id: session_info.id,
+            user_id: session_info.user_id,
+            expression: Some(format!("{} {} {}", input_data.num1, operator.symbol, input_data.num2)),
+            mode: context.mode,
+            exact_result,
+        }).await.map_err(Error::from)?;
+
+        Ok(warp::reply::json(&CalculateResultJson {
             num1: input_data.num1,
-            num2: input_data.num2,  
-            operator_id: input_data.operator_id,
-            result: None,
+            num2: input_data.num2,
+            operator_id: operator.id,
+            mode: context.mode,
+            result,
+        }))
+    }
+
+    pub async fn operators(registry: Arc<Registry>) -> Result<impl warp::Reply, warp::Rejection> {
+        Ok(warp::reply::json(&OperatorsJson {
+            operators: registry.list().collect(),
+        }))
+    }
+
+    pub async fn evaluate(session_info: Session, input_data: EvaluateJson, repos: Repositories, limits: Limits) -> Result<impl warp::Reply, warp::Rejection> {
+        let ast = expr::parse(&input_data.expression).map_err(|err| Error::BadRequest(err.to_string()))?;
+        let context = limits.context(input_data.mode, input_data.scale, input_data.ieee).map_err(Error::from)?;
+        let value = ast.eval(&context).map_err(Error::from)?;
+        let value = context.finish(value);
+        let (result, exact_result) = context.render(&value);
+
+        repos.calculations.insert(NewCalculation {
+            num1: None,
+            num2: None,
+            operator_id: None,
+            result: value.to_f64(),
+            session_id: session_info.id,
+            user_id: session_info.user_id,
+            expression: Some(input_data.expression.clone()),
+            mode: context.mode,
+            exact_result,
+        }).await.map_err(Error::from)?;
+
+        Ok(warp::reply::json(&EvaluateResultJson {
+            expression: input_data.expression,
+            mode: context.mode,
+            result,
+            ast,
+        }))
+    }
+
+    /// Refused with `429` while `throttle` blocks the client's address or the
+    /// account; a wrong name or password counts against both. On success the
+    /// client gets a new session cookie and the one it came with is revoked.
+    pub async fn login(session_info: Session, login_data: TestLoginJson, client: Client, repos: Repositories, issuer: Arc<SessionIssuer>, passwords: Passwords, throttle: Throttle) -> Result<impl warp::Reply, warp::Rejection> {
+        let attempt = throttle.begin(client.ip.as_deref(), Some(&login_data.name)).map_err(Error::TooManyRequests)?;
+        let Some(user_info) = get_user_info_by_login(&repos, passwords, &login_data).await? else {
+            return Err(Error::InvalidCredentials.into());
         };
+        attempt.succeeded();
 
-        match input_data.operator_id {
-            1 => result_data.result = Some(result_data.num1 + result_data.num2),
-            2 => result_data.result = Some(result_data.num1 - result_data.num2),
-            3 => result_data.result = Some(result_data.num1 * result_data.num2),
-            4 => result_data.result = Some(result_data.num1 / result_data.num2),
-            _ => result_data.result = None,
-        }
-
-        let session_info = get_session_info(db.clone(), session_hash).await;
-
-        if let Err(mes) = session_info {
-            println!("sql  {mes:?}");
-            return Ok("SESSION GET ERRROR not found session".into_response());
-        }
-
-        let session_info = session_info.unwrap();
-
-        let db_response = db.lock().await.execute("insert into calculations (num1, num2, operator_id, result, session_id, user_id) values (?1, ?2, ?3, ?4, ?5, ?6);", params![
-            &result_data.num1,
-            &result_data.num2,
-            &result_data.operator_id,
-            &result_data.result,
-            session_info.id,
-            session_info.user_id,
-        ]);
-        
-        match db_response {
-            Ok(_) => Ok(warp::reply::json(&result_data).into_response()),
-            Err(massage) => {
-                println!("sql another {massage:?}");
-                Ok(warp::reply::with_status("ERROR_WITH_DB", StatusCode::INTERNAL_SERVER_ERROR).into_response())
-            },
-        }
-    }
-
-    pub async fn login(session_hash: String, login_data: TestLoginJson, db: Database) -> Result<impl warp::Reply, warp::Rejection> {
-        let user_info = get_user_info_by_login(db.clone(), login_data).await;
-
-        println!("{user_info:?}");
-
-        match user_info {
-            Ok(user_info) => {
-                let db_response = db.lock().await.execute("update sessions set is_auth=true, user_id=?1, name=?2 where hash=?3;", params![
-                user_info.id,
-                &user_info.name,
-                session_hash,
-            ]);
-            
-            match db_response {
-                Ok(_) => Ok(warp::reply().into_response()),
-                Err(massage) => {
-                    println!("{massage:?}");
-                    Ok(warp::reply::with_status("ERROR_WITH_DB", StatusCode::INTERNAL_SERVER_ERROR).into_response())
-                },
-            }
-            },
-            Err(mess) => Ok(warp::reply::with_status("USER NOT EXIST", StatusCode::UNAUTHORIZED).into_response()),
+        start_user_session(session_info, &user_info, login_data.adopt_history, &client, &repos, &issuer).await
+    }
+
+    /// A taken name counts against the client's address, which stops it
+    /// from probing which names exist.
+    pub async fn register(session_info: Session, register_data: TestLoginJson, client: Client, repos: Repositories, issuer: Arc<SessionIssuer>, passwords: Passwords, throttle: Throttle) -> Result<impl warp::Reply, warp::Rejection> {
+        let attempt = throttle.begin(client.ip.as_deref(), None).map_err(Error::TooManyRequests)?;
+        let taken = || Error::Conflict(format!("user '{}' already exists", register_data.name));
+        // Checked first to skip hashing; the unique index settles races.
+        if repos.users.find_by_name(&register_data.name).await.map_err(Error::from)?.is_some() {
+            return Err(taken().into());
         }
+        let Some(user_info) = register_new_user(&repos, &passwords, &register_data).await? else {
+            return Err(taken().into());
+        };
+        attempt.succeeded();
+
+        start_user_session(session_info, &user_info, register_data.adopt_history, &client, &repos, &issuer).await
     }
 
-    pub async fn register(session_hash: String, register_data: TestLoginJson, db: Database) -> Result<impl warp::Reply, warp::Rejection> {
-        let register_result = register_new_user(db.clone(), &register_data).await;
+    /// Replaces the client's session with a logged-in one for `user_info`.
+    async fn start_user_session(session_info: Session, user_info: &User, adopt_history: bool, client: &Client, repos: &Repositories, issuer: &SessionIssuer) -> Result<impl warp::Reply, warp::Rejection> {
+        let token = new_session_token();
+        let adopted = repos.sessions.log_in(&session_info.hash, &hash_session_token(&token), user_info, adopt_history, client).await.map_err(Error::from)?;
+        if adopted > 0 {
+            log::info!("user {} adopted {adopted} calculations of session {}", user_info.id, session_info.id);
+        }
 
-        println!("{register_result:?}");
+        let cookie = session_cookie(&token, issuer.secure_cookie);
+        Ok(warp::reply::with_header(warp::reply::json(&LoginResultJson { user_id: user_info.id, adopted }), "set-cookie", cookie))
+    }
 
-        match register_result {
-            Ok(_) => {
-                let result = login(session_hash, register_data, db).await;
-                match result {
-                    Ok(ok) => Ok(ok.into_response()),
-                    Err(rej) => Err(rej),
-                }
-            },
-            // Err(mess) => Ok(warp::reply().into_response()),
-            Err(mess) => Ok(warp::reply::with_status("ERROR WITH REGISTER", StatusCode::UNAUTHORIZED).into_response()),
+    pub async fn get_users(_user_info: User, repos: Repositories) -> Result<impl warp::Reply, warp::Rejection> {
+        let users = repos.users.list().await.map_err(Error::from)?;
+        Ok(warp::reply::json(&UsersJson { users: users.into_iter().map(UserJson::from).collect() }))
+    }
+
+    pub async fn delete_user(user_info: User, user_id: i32, repos: Repositories) -> Result<impl warp::Reply, warp::Rejection> {
+        if !repos.users.delete(user_id).await.map_err(Error::from)? {
+            return Err(Error::NotFound(format!("user {user_id}")).into());
         }
+        log::info!("user {} deleted user {user_id}", user_info.id);
+        Ok(warp::reply())
     }
 
-    pub async fn get_users(session_hash: String, db: Database) -> Result<impl warp::Reply, warp::Rejection> {
-        let session_info = get_session_info(db.clone(), session_hash).await;
-        if let Err(mes) = session_info {
-            println!("{mes:?}");
-            return Ok("USERS GET ERRROR1".into_response());
+    pub async fn session_info(session_info: Session) -> Result<impl warp::Reply, warp::Rejection> {
+        Ok(warp::reply::json(&session_info))
+    }
+
+    pub async fn history(session_info: Session, query: HistoryQuery, repos: Repositories) -> Result<impl warp::Reply, warp::Rejection> {
+        let filter = HistoryFilter::try_from(query).map_err(Error::BadRequest)?;
+        let page = repos.calculations.page(history_owner(&session_info), &filter).await.map_err(Error::from)?;
+
+        Ok(warp::reply::json(&page))
+    }
+
+    /// Logged-in users see their account's history, anonymous sessions
+    /// their own.
+    fn history_owner(session_info: &Session) -> Owner {
+        match (session_info.is_auth, session_info.user_id) {
+            (true, Some(user_id)) => Owner::User(user_id),
+            _ => Owner::Session(session_info.id),
         }
-        let session_info = session_info.unwrap();
+    }
+
+    /// Clears the history. It can be brought back with `undo_delete_history`
+    /// until the undo window runs out.
+    pub async fn delete_history(session_info: Session, repos: Repositories, undo_window: Duration) -> Result<impl warp::Reply, warp::Rejection> {
+        let deleted = repos.calculations.delete_all(history_owner(&session_info)).await.map_err(Error::from)?;
+
+        Ok(warp::reply::json(&DeletedJson { deleted, undo_until: unix_now() + undo_window.as_secs() as i64 }))
+    }
 
-        if session_info.is_auth == false {
-            return Ok(warp::reply::with_status(warp::reply(), StatusCode::from_u16(228).unwrap()).into_response());
+    /// Streams the whole history as a file download, a page at a time, so
+    /// long histories never sit in memory at once.
+    pub async fn export_history(session_info: Session, query: ExportQuery, repos: Repositories, registry: Arc<Registry>) -> Result<impl warp::Reply, warp::Rejection> {
+        let owner = history_owner(&session_info);
+        let format = query.format;
+        let (mut sender, body) = warp::hyper::Body::channel();
+        tokio::spawn(async move {
+            if let Err(massage) = stream_history(&mut sender, owner, format, repos, registry).await {
+                log::warn!("history export for {owner:?} failed: {massage}");
+                // Cut the response short so the client sees a failed
+                // download instead of a silently truncated file.
+                sender.abort();
+            }
+        });
+
+        warp::http::Response::builder()
+            .header(warp::http::header::CONTENT_TYPE, format.content_type())
+            .header(warp::http::header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", export::filename(format, unix_now())))
+            .body(body)
+            .map_err(|err| Error::Internal(err.to_string()).into())
+    }
+
+    async fn stream_history(sender: &mut warp::hyper::body::Sender, owner: Owner, format: ExportFormat, repos: Repositories, registry: Arc<Registry>) -> Result<(), String> {
+        let mut encoder = export::Encoder::new(format);
+        let mut query = HistoryQuery { limit: Some(history::MAX_LIMIT), order: Order::Asc, ..Default::default() };
+        loop {
+            let filter = HistoryFilter::try_from(query.clone())?;
+            let page = repos.calculations.page(owner, &filter).await.map_err(|err| err.to_string())?;
+            let rows: Vec<ExportRow> = page.history.iter().map(|calculation| ExportRow::new(calculation, &registry)).collect();
+            let chunk = encoder.encode(&rows).map_err(|err| err.to_string())?;
+            if sender.send_data(chunk.into()).await.is_err() {
+                // The client went away.
+                return Ok(());
+            }
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => return Ok(()),
+            }
         }
+    }
 
-        let users = get_users_from_db(db.clone()).await;
-        if let Err(mes) = users {
-            println!("{mes:?}");
-            return Ok("USERS GET ERRROR2".into_response());
+    pub async fn delete_history_entry(id: i32, session_info: Session, repos: Repositories, undo_window: Duration) -> Result<impl warp::Reply, warp::Rejection> {
+        // Someone else's calculation looks the same as a missing one.
+        if !repos.calculations.delete(history_owner(&session_info), id).await.map_err(Error::from)? {
+            return Err(Error::NotFound(format!("calculation {id}")).into());
         }
-        let users = users.unwrap();
 
-        Ok(warp::reply::json(&users).into_response())
+        Ok(warp::reply::json(&DeletedJson { deleted: 1, undo_until: unix_now() + undo_window.as_secs() as i64 }))
     }
 
-    pub async fn delete_user(session_hash: String, user_id: i32, db: Database) -> Result<impl warp::Reply, warp::Rejection> {
-        let session_info = get_session_info(db.clone(), session_hash).await;
-        if let Err(mes) = session_info {
-            println!("{mes:?}");
-            return Ok("delete user ERRROR1".into_response());
+    /// Restores everything deleted within the undo window.
+    pub async fn undo_delete_history(session_info: Session, repos: Repositories, undo_window: Duration) -> Result<impl warp::Reply, warp::Rejection> {
+        let restored = repos.calculations.restore(history_owner(&session_info), undo_window).await.map_err(Error::from)?;
+
+        Ok(warp::reply::json(&RestoredJson { restored }))
+    }
+
+    fn unix_now() -> i64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+    }
+
+    /// The user list; with `include_hashes`, a dump that `import_users`
+    /// can restore, password hashes included.
+    pub async fn export_users(user_info: User, query: ExportUsersQuery, repos: Repositories) -> Result<impl warp::Reply, warp::Rejection> {
+        let users = repos.users.list().await.map_err(Error::from)?;
+        if query.include_hashes {
+            log::info!("user {} exported the password hashes of {} users", user_info.id, users.len());
+            return Ok(warp::reply::json(&UserDumpJson { users }));
         }
-        let session_info = session_info.unwrap();
+        Ok(warp::reply::json(&UsersJson { users: users.into_iter().map(UserJson::from).collect() }))
+    }
+
+    /// Imports users in one transaction. Nothing is committed for a dry run
+    /// or when any row is invalid or conflicts under `on_conflict=fail`.
+    pub async fn import_users(user_info: User, query: ImportQuery, body: Bytes, repos: Repositories) -> Result<impl warp::Reply, warp::Rejection> {
+        let records = import::parse(query.format, &body)?;
 
-        if session_info.is_auth == false {
-            return Ok(warp::reply::with_status(warp::reply(), StatusCode::from_u16(228).unwrap()).into_response());
+        let report = repos.users.import(records, query).await.map_err(Error::from)?;
+        log::info!("user {} imported users: {} created, {} updated, {} skipped, {} failed, applied {}",
+            user_info.id, report.created, report.updated, report.skipped, report.failed, report.applied);
+
+        Ok(warp::reply::json(&report))
+    }
+
+    /// Backs `filters::with_permission`: the session must be logged in and
+    /// the user's role must grant `permission`.
+    pub async fn authorize(repos: Repositories, session_info: Session, permission: Permission) -> Result<User, warp::Rejection> {
+        let user_id = logged_in_user_id(&session_info)?;
+        let user_info = repos.users.find_by_id(user_id).await
+            .map_err(Error::from)?
+            .ok_or(Error::NotLoggedIn)?;
+        let role = repos.users.role(&user_info.role).await.map_err(Error::from)?;
+        if !role.allows(permission) {
+            return Err(Error::Forbidden(permission).into());
         }
+        Ok(user_info)
+    }
 
-        let user_info = get_user_info_by_id(session_info.user_id.unwrap(), db.clone()).await;
-        if let Err(mes) = user_info {
-            println!("{mes:?}");
-            return Ok("USERS GET ERRROR2".into_response());
+    fn logged_in_user_id(session_info: &Session) -> Result<i32, Error> {
+        match session_info.user_id {
+            Some(user_id) if session_info.is_auth => Ok(user_id),
+            _ => Err(Error::NotLoggedIn),
         }
-        let user_info = user_info.unwrap();
+    }
 
-        if user_info.role != "moderling" {
-            return Ok(warp::reply::with_status(warp::reply(), StatusCode::from_u16(229).unwrap()).into_response());
+    async fn get_user_info_by_login(repos: &Repositories, passwords: Passwords, login_data: &TestLoginJson) -> Result<Option<User>, Error> {
+        let user_info = repos.users.find_by_name(&login_data.name).await?;
+
+        let stored = user_info.as_ref().map(|user_info| (user_info.name.clone(), user_info.auth_hash.clone()));
+        let password = login_data.password.clone();
+        // Argon2 is deliberately slow, keep it off the async executor.
+        let (verification, new_hash) = tokio::task::spawn_blocking(move || {
+            let verification = match stored {
+                Some((name, auth_hash)) => passwords.verify(&name, &password, &auth_hash),
+                None => passwords.verify_unknown(&password),
+            };
+            let new_hash = match verification {
+                Verification::ValidNeedsRehash => passwords.hash(&password).ok(),
+                _ => None,
+            };
+            (verification, new_hash)
+        }).await.map_err(|err| Error::Internal(format!("password check failed: {err}")))?;
+
+        let Some(mut user_info) = user_info.filter(|_| verification != Verification::Invalid) else {
+            return Ok(None);
+        };
+        if let Some(new_hash) = new_hash {
+            repos.users.set_auth_hash(user_info.id, &new_hash).await?;
+            user_info.auth_hash = new_hash;
         }
+        Ok(Some(user_info))
+    }
 
-        println!("uid delete user{user_id}");
-        let db_response = db.lock().await.execute("delete from users where id = ?1;",
-            [user_id]);
-        match db_response {
-            Ok(_) => Ok(warp::reply().into_response()),
-            Err(massage) => Ok(warp::reply::with_status(massage.to_string(),
-             StatusCode::INTERNAL_SERVER_ERROR).into_response()),
+    /// The new user, or `None` if the name is taken.
+    async fn register_new_user(repos: &Repositories, passwords: &Passwords, register_data: &TestLoginJson) -> Result<Option<User>, Error> {
+        let passwords = passwords.clone();
+        let password = register_data.password.clone();
+        let auth_hash = tokio::task::spawn_blocking(move || passwords.hash(&password))
+            .await
+            .map_err(|err| Error::Internal(format!("password hashing failed: {err}")))?
+            .map_err(|err| Error::Internal(format!("password hashing failed: {err}")))?;
+        let role = "normise".to_string();
+        let Some(id) = repos.users.create(&register_data.name, &auth_hash, &role).await? else {
+            return Ok(None);
+        };
+        Ok(Some(User { id, name: register_data.name.clone(), auth_hash, role }))
+    }
+
+    /// Looks up a live session by its cookie value and marks it as seen.
+    pub async fn get_session_info(repos: Repositories, session_token: String, lifetimes: SessionLifetimes) -> Result<Option<Session>, db::Error> {
+        repos.sessions.touch(&hash_session_token(&session_token), lifetimes).await
+    }
+
+    /// Periodically purges expired sessions, calculations whose undo
+    /// window has run out, and forgotten login failures.
+    pub async fn purge_expired_task(repos: Repositories, throttle: Throttle, lifetimes: SessionLifetimes, undo_window: Duration) {
+        let mut interval = tokio::time::interval(lifetimes.cleanup_interval);
+        loop {
+            interval.tick().await;
+            match repos.sessions.purge_expired(lifetimes).await {
+                Ok(0) => {},
+                Ok(purged) => log::info!("purged {purged} expired or revoked sessions"),
+                Err(massage) => log::warn!("session cleanup failed: {massage}"),
+            }
+            match repos.calculations.purge_deleted(undo_window).await {
+                Ok(0) => {},
+                Ok(purged) => log::info!("purged {purged} deleted calculations"),
+                Err(massage) => log::warn!("history cleanup failed: {massage}"),
+            }
+            throttle.prune();
         }
     }
 
-    // pub async fn logout(session_hash: String, db: Database) -> Result<impl warp::Reply, warp::Rejection> {
-    //     let db_response = db.lock().await.execute("update sessions set is_auth=false, user_id=?1, name=?2 where hash=?3;", params![
-    //         user_info.id,
-    //         &user_info.name,
-    //         session_hash,
-    //     ])
-        
-    //     match db_response {
-    //         Ok(_) => Ok(warp::reply().into_response()),
-    //         Err(massage) => {
-    //             println!("{massage:?}");
-    //             Ok(warp::reply::with_status("ERROR_WITH_DB", StatusCode::INTERNAL_SERVER_ERROR).into_response())
-    //         },
-    //     }
-    // }
+    pub async fn user_have_not_cookies_situation(repos: Repositories, issuer: Arc<SessionIssuer>, err: warp::Rejection) -> Result<impl warp::Reply, std::convert::Infallible> {
+        log::debug!("issuing a new session after {err:?}");
+
+        create_new_session(repos, issuer).await
+    }
+
+    /// A fresh session cookie value: 256 bits from the OS CSPRNG.
+    fn new_session_token() -> String {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
 
-    pub async fn session_info(session_hash: String, db: Database) -> Result<impl warp::Reply, warp::Rejection> {
-        let session_info = get_session_info(db.clone(), session_hash).await;
-        if let Err(mes) = session_info {
-            println!("{mes:?}");
-            return Ok("SESSION GET ERRROR".into_response());
+    /// `sessions.hash` holds this digest, never the cookie value itself, so
+    /// a leaked database cannot be replayed as cookies.
+    fn hash_session_token(token: &str) -> String {
+        format!("{:x}", Sha256::digest(token.as_bytes()))
+    }
+
+    fn session_cookie(token: &str, secure: bool) -> String {
+        let secure = if secure { "; Secure" } else { "" };
+        format!("session_hash={token}; Path=/; HttpOnly; SameSite=Lax{secure}")
+    }
+
+    /// Stores a new anonymous session and returns the `set-cookie` value
+    /// that hands it to the client.
+    async fn issue_session(repos: &Repositories, issuer: &SessionIssuer) -> Result<String, db::Error> {
+        let new_session_token = new_session_token();
+        let new_session_hash = hash_session_token(&new_session_token);
+
+        let name_seed = rand::random::<u32>();
+        let new_session_name = issuer.names[name_seed as usize % issuer.names.len()].clone() + &(name_seed % 100).to_string();
+        repos.sessions.create(&new_session_hash, &new_session_name).await?;
+        Ok(session_cookie(&new_session_token, issuer.secure_cookie))
+    }
+
+    pub async fn create_new_session(repos: Repositories, issuer: Arc<SessionIssuer>) -> Result<impl warp::Reply, std::convert::Infallible> {
+        match issue_session(&repos, &issuer).await {
+            Ok(cookie) => Ok(warp::reply::with_header(warp::reply(), "set-cookie", cookie).into_response()),
+            Err(massage) => Ok(Error::from(massage).to_response()),
         }
-        let session_info = session_info.unwrap();
+    }
+
+    /// Revokes the current session, so its cookie stops working even if it
+    /// was copied, and hands out a fresh anonymous one.
+    pub async fn logout(session_token: String, repos: Repositories, issuer: Arc<SessionIssuer>) -> Result<impl warp::Reply, warp::Rejection> {
+        repos.sessions.revoke(&hash_session_token(&session_token)).await.map_err(Error::from)?;
+        let cookie = issue_session(&repos, &issuer).await.map_err(Error::from)?;
+        Ok(warp::reply::with_header(warp::reply(), "set-cookie", cookie))
+    }
 
-        Ok(warp::reply::json(&session_info).into_response())
+    /// Revokes every session of the logged-in user, this one included, and
+    /// hands out a fresh anonymous one.
+    pub async fn logout_everywhere(session_info: Session, repos: Repositories, issuer: Arc<SessionIssuer>) -> Result<impl warp::Reply, warp::Rejection> {
+        let user_id = logged_in_user_id(&session_info)?;
+        let revoked = repos.sessions.revoke_all(user_id).await.map_err(Error::from)?;
+        log::info!("user {user_id} logged out of {revoked} sessions");
+        let cookie = issue_session(&repos, &issuer).await.map_err(Error::from)?;
+        Ok(warp::reply::with_header(warp::reply::json(&RevokedJson { revoked }), "set-cookie", cookie))
     }
 
-    pub async fn history(session_hash: String, db: Database) -> Result<impl warp::Reply, warp::Rejection> {
-        let session_info = get_session_info(db.clone(), session_hash).await;
-        if let Err(mes) = session_info {
-            println!("{mes:?}");
-            return Ok("SESSION GET ERRROR".into_response());
+    /// The logged-in user's live sessions, for spotting one left open on
+    /// another machine.
+    pub async fn sessions(session_info: Session, repos: Repositories, lifetimes: SessionLifetimes) -> Result<impl warp::Reply, warp::Rejection> {
+        let user_id = logged_in_user_id(&session_info)?;
+        let sessions = repos.sessions.list_live(user_id, lifetimes).await.map_err(Error::from)?;
+        let sessions = sessions.into_iter().map(|session| ActiveSessionJson {
+            id: session.id,
+            created_at: session.created_at,
+            last_seen_at: session.last_seen_at,
+            user_agent: session.user_agent,
+            ip: session.ip,
+            current: session.id == session_info.id,
+        }).collect();
+        Ok(warp::reply::json(&SessionsJson { sessions }))
+    }
+
+    pub async fn revoke_session(id: i32, session_info: Session, repos: Repositories) -> Result<impl warp::Reply, warp::Rejection> {
+        let user_id = logged_in_user_id(&session_info)?;
+        // Another user's session looks the same as a missing one.
+        if !repos.sessions.revoke_for_user(user_id, id).await.map_err(Error::from)? {
+            return Err(Error::NotFound(format!("session {id}")).into());
         }
-        let session_info = session_info.unwrap();
+        log::info!("user {user_id} revoked session {id}");
+        Ok(warp::reply::json(&RevokedJson { revoked: 1 }))
+    }
 
-        let history;
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::error::Error;
+        use crate::history::{Order, SortKey};
+        use crate::import::{ImportFormat, ImportQuery};
+        use calc_core::numeric::{Mode, Operand};
+        use crate::password::HashCost;
+        use crate::throttle::Policy;
+
+        fn passwords() -> Passwords {
+            Passwords::new(HashCost { memory_kib: 8, iterations: 1, parallelism: 1 }).unwrap()
+        }
 
-        if session_info.is_auth {
-            history = get_history_by_user_id(db.clone(), session_info.user_id.unwrap()).await;
-        } else {
-            history = get_history_by_session(db.clone(), session_info.id).await;
+        fn throttle() -> Throttle {
+            Throttle::new(Policy::default())
         }
 
-        match history {
-            Ok(history) => Ok(warp::reply::json(&history).into_response()),
-            Err(err) => Ok(warp::reply::with_header(warp::reply::with_status("HISTORY ERROR", StatusCode::INTERNAL_SERVER_ERROR), "err message", err.to_string()).into_response())
+        async fn new_session(repos: &Repositories) -> Session {
+            let token = new_session_token();
+            repos.sessions.create(&hash_session_token(&token), "tester").await.unwrap();
+            get_session_info(repos.clone(), token, SessionLifetimes::default()).await.unwrap().unwrap()
         }
-    }
 
-    pub async fn delete_history(session_hash: String, db: Database) -> Result<impl warp::Reply, warp::Rejection> {
-        let session_info = get_session_info(db.clone(), session_hash).await;
-        if let Err(mes) = session_info {
-            println!("{mes:?}");
-            return Ok("SESSION GET ERRROR".into_response());
+        fn issuer() -> Arc<SessionIssuer> {
+            Arc::new(SessionIssuer { names: vec!["tester".to_string()], secure_cookie: false })
         }
-        let session_info = session_info.unwrap();
 
-        let result;
+        /// Logs `session` in as `user` and returns the session that replaces it.
+        async fn log_in(repos: &Repositories, session: &Session, user: &User) -> Session {
+            let token = new_session_token();
+            repos.sessions.log_in(&session.hash, &hash_session_token(&token), user, true, &Client::default()).await.unwrap();
+            get_session_info(repos.clone(), token, SessionLifetimes::default()).await.unwrap().unwrap()
+        }
 
-        if session_info.is_auth {
-            result = delete_history_by_user_id(db.clone(), session_info.user_id.unwrap()).await;
-        } else {
-            result = delete_history_by_session(db.clone(), session_info.id).await;
+        /// The body of a login reply and the session its cookie names.
+        async fn logged_in(repos: &Repositories, reply: impl Reply) -> (serde_json::Value, Session) {
+            let response = reply.into_response();
+            let cookie = response.headers()["set-cookie"].to_str().unwrap();
+            let token = cookie.strip_prefix("session_hash=").unwrap().split(';').next().unwrap().to_string();
+            let session = get_session_info(repos.clone(), token, SessionLifetimes::default()).await.unwrap().unwrap();
+            (body_json(response).await, session)
         }
 
-        match result {
-            Ok(history) => Ok(warp::reply::json(&history).into_response()),
-            Err(err) => Ok(warp::reply::with_header(warp::reply::with_status("HISTORY ERROR", StatusCode::INTERNAL_SERVER_ERROR), "err message", err.to_string()).into_response())
+        async fn body_json(reply: impl Reply) -> serde_json::Value {
+            let body = warp::hyper::body::to_bytes(reply.into_response().into_body()).await.unwrap();
+            serde_json::from_slice(&body).unwrap()
         }
-    }
 
-    pub async fn export_users(session_hash: String, db: Database) -> Result<impl warp::Reply, warp::Rejection> {
-        let users = get_users_from_db(db.clone()).await.unwrap();
-        Ok(warp::reply::json(&users))
-    }
+        fn error(rejection: &warp::Rejection) -> &Error {
+            rejection.find::<Error>().expect("rejection carries an api error")
+        }
 
-    async fn get_history_by_session(db: Database, session_id: i32) -> Result<HistoryJson, rusqlite::Error> {
-        let db = db.lock().await;
-        let mut stmt = db.prepare("select id, num1, num2, operator_id, result, session_id, user_id from calculations where session_id=?1")?;
-        let history = stmt.query_map(params![session_id], |row| {
-            Ok(Calculation {
-                id: row.get(0)?,
-                num1: row.get(1)?,
-                num2: row.get(2)?,
-                operator_id: row.get(3)?,
-                result: row.get(4)?,
-                session_id: row.get(5)?,
-                user_id: row.get(6)?,
-            })
-        })?;
-        let history: Vec<Calculation> = history.map(|e| e.unwrap()).collect();
-        Ok(HistoryJson { history })
-    }
+        fn operation(num1: f64, num2: f64, operator_id: i32) -> CalculateJson {
+            CalculateJson { num1: Operand::Number(num1), num2: Operand::Number(num2), operator_id, mode: Mode::F64, scale: None, ieee: false }
+        }
 
+        fn credentials(name: &str, password: &str) -> TestLoginJson {
+            TestLoginJson { name: name.to_string(), password: password.to_string(), adopt_history: true }
+        }
 
-    async fn delete_history_by_session(db: Database, session_id: i32) -> Result<(), rusqlite::Error> {
-        let mut stmt = db.lock().await.execute("delete from calculations where session_id=?1", [session_id]);
-        Ok(())
-    }
+        #[tokio::test]
+        async fn calculate_stores_history_for_the_session() {
+            let repos = Repositories::memory();
+            let session = new_session(&repos).await;
+            let input = operation(2.0, 10.0, 5);
 
-    async fn delete_history_by_user_id(db: Database, user_id: i32) -> Result<(), rusqlite::Error> {
-        let mut stmt = db.lock().await.execute("delete from calculations where user_id=?1", [user_id]);
-        Ok(())
-    }
+            let reply = calculate(session.clone(), input, Arc::new(Registry::builtin()), repos.clone(), Limits::default()).await.unwrap();
+            assert_eq!(body_json(reply).await["result"], 1024.0);
 
-    async fn get_history_by_user_id(db: Database, user_id: i32) -> Result<HistoryJson, rusqlite::Error> {
-        let db = db.lock().await;
-        let mut stmt = db.prepare("select id, num1, num2, operator_id, result, session_id, user_id from calculations where user_id=?1")?;
-        let history = stmt.query_map(params![user_id], |row| {
-            Ok(Calculation {
-                id: row.get(0)?,
-                num1: row.get(1)?,
-                num2: row.get(2)?,
-                operator_id: row.get(3)?,
-                result: row.get(4)?,
-                session_id: row.get(5)?,
-                user_id: row.get(6)?,
-            })
-        })?;
-        let history: Vec<Calculation> = history.map(|e| e.unwrap()).collect();
-        Ok(HistoryJson { history })
-    }
-
-    async fn get_users_from_db(db: Database) -> Result<UsersJson, rusqlite::Error> {
-        let db = db.lock().await;
-        let mut stmt = db.prepare("select id, name, auth_hash, role from users")?;
-        let data = stmt.query_map(params![], |row| {
-            Ok(User{
-                id: row.get(0)?,
-                name: row.get(1)?,
-                auth_hash: row.get(2)?,
-                role: row.get(3)?,
-            })
-        })?;
-        let data: Vec<User> = data.map(|e| e.unwrap()).collect();
-        Ok(UsersJson { users: data })
-    }
-
-    async fn get_user_info_by_id(user_id: i32, db: Database) -> Result<User, rusqlite::Error> {
-        let db_response = db.lock().await.query_row("select id, name, auth_hash, role from users where id = ?1", params![user_id], |row| {
-            Ok(User{
-                id: row.get(0)?,
-                name: row.get(1)?,
-                auth_hash: row.get(2)?,
-                role: row.get(3)?,
-            })
-        });
-        match db_response {
-            Ok(user_info) => Ok(user_info),
-            Err(massage) => Err(massage),
-        }
-    }
-
-    async fn get_user_info_by_login(db: Database, login_data: TestLoginJson) -> Result<User, rusqlite::Error> {
-        let auth_hash = login_data.name + ":" + &login_data.password;
-        let db_response = db.lock().await.query_row("select id, name, auth_hash, role from users where auth_hash = ?1;", [&auth_hash],
-         |row| Ok(User{
-            id: row.get(0)?,
-            name: row.get(1)?,
-            auth_hash: row.get(2)?,
-            role: row.get(3)?,
-        }));
-        match db_response {
-            Ok(user_info) => Ok(user_info),
-            Err(massage) => Err(massage),
-        }
-    }
-
-    async fn register_new_user(db: Database, register_data: &TestLoginJson) -> Result<(), rusqlite::Error> {
-        let auth_hash = register_data.clone().name + ":" + &register_data.password;
-        let db_response = db.lock().await.execute("insert into users(name, auth_hash, role) values(?1, ?2, ?3);",
-         [&register_data.name, &auth_hash, "normise"]);
-        match db_response {
-            Ok(_) => Ok(()),
-            Err(massage) => Err(massage),
-        }
-    }
-
-    async fn get_session_info(db: Database, session_hash: String) -> Result<Session, rusqlite::Error> {
-        let db_response = db.lock().await.query_row("select id, hash, is_auth, user_id, name from sessions where hash = ?1;", [&session_hash], |row| Ok(Session{
-                id: row.get(0)?,
-                hash: row.get(1)?,
-                is_auth: row.get(2)?,
-                user_id: row.get(3)?,
-                name: row.get(4)?,
-            }));
-        match db_response {
-            Ok(session_info) => Ok(session_info),
-            Err(massage) => Err(massage),
-            // Error(error_massage) => Err(warp::reject::custom(error_on_db)),
-        }
-    }
-
-    pub async fn user_have_not_cookies_situation(db: Database, err: warp::Rejection) -> Result<impl warp::Reply, std::convert::Infallible> {
-        println!("you are in error situation {err:?}");
-
-        create_new_session("".to_string(), db).await
-    }
-
-    pub async fn create_new_session(session_hash: String, db: Database) -> Result<impl warp::Reply, std::convert::Infallible> {
-        let hash_seed = rand::random::<u32>();  
-        let mut hasher = DefaultHasher::new();
-        hash_seed.hash(&mut hasher);
-        let new_session_hash = format!("{:x}", md5::compute(hasher.finish().to_string()));
-
-        let mut file = File::open("data/names.txt").unwrap();
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).unwrap();
-        let names: Vec<&str> = contents.split('\n').collect();
-        let new_session_name = names[usize::try_from(hash_seed % 115).unwrap()].to_owned() + &(hash_seed % 100).to_string();
-        let db_response = db.lock().await.execute("insert into sessions (hash, is_auth, name) values (?1, ?2, ?3);", params![&new_session_hash, false, &new_session_name]);
-        
-        match db_response {
-            Ok(_) => Ok(warp::reply::with_header(
-                warp::reply(),
-                "set-cookie",
-                format!("session_hash={new_session_hash}; path=/")).into_response()),
-            Err(massage) => {
-                println!("{massage}");
-                Ok(warp::reply::with_status("ERROR_WITH_DB", StatusCode::INTERNAL_SERVER_ERROR).into_response())
-            },
-        }
-    }
-
-    // pub async fn decor(db: Database) -> Fn {
-    //     user_have_not_cookies_situation
-    // }
-
-    // pub async fn login(session_hash: String, db: Database) -> Result<impl warp::Reply, warp::Rejection> {
-    //     println!("{session_hash}");
-    //     Ok(warp::reply())
-    // }
-    
-    // pub async fn register(session_hash: String, db: Database) -> Result<impl warp::Reply, warp::Rejection> {
-    //     println!("{session_hash}");
-    //     Ok(warp::reply())
-    // }
-
-    // pub async fn history(session_hash: String, db: Database) -> Result<impl warp::Reply, warp::Rejection> {
-    //     println!("{session_hash}");
-    //     Ok(warp::reply())
-    // }
+            let history = body_json(history(session, HistoryQuery::default(), repos).await.unwrap()).await;
+            assert_eq!(history["history"][0]["expression"], "2 ^ 10");
+            assert!(history["next_cursor"].is_null());
+        }
+
+        #[tokio::test]
+        async fn calculate_rejects_unknown_operator() {
+            let repos = Repositories::memory();
+            let session = new_session(&repos).await;
+            let input = operation(1.0, 1.0, 42);
+
+            let err = calculate(session.clone(), input, Arc::new(Registry::builtin()), repos.clone(), Limits::default()).await.err().unwrap();
+            assert!(matches!(error(&err), Error::BadRequest(_)));
+            let filter = HistoryFilter::try_from(HistoryQuery::default()).unwrap();
+            assert!(repos.calculations.page(Owner::Session(session.id), &filter).await.unwrap().history.is_empty());
+        }
+
+        #[tokio::test]
+        async fn exact_modes_keep_every_digit() {
+            let repos = Repositories::memory();
+            let registry = Arc::new(Registry::builtin());
+            let session = new_session(&repos).await;
+            let decimal = CalculateJson { mode: Mode::Decimal, ..operation(0.1, 0.2, 1) };
+            let reply = calculate(session.clone(), decimal, registry.clone(), repos.clone(), Limits::default()).await.unwrap();
+            assert_eq!(body_json(reply).await["result"], 0.3);
+
+            let third = CalculateJson { mode: Mode::Rational, ..operation(1.0, 3.0, 4) };
+            let reply = calculate(session.clone(), third, registry.clone(), repos.clone(), Limits::default()).await.unwrap();
+            assert_eq!(body_json(reply).await["result"], "1/3");
+
+            let input = EvaluateJson { expression: "2^70 + 1".to_string(), mode: Mode::Rational, scale: None, ieee: false };
+            let reply = body_json(evaluate(session.clone(), input, repos.clone(), Limits::default()).await.unwrap()).await;
+            assert_eq!(reply["result"], "1180591620717411303425");
+
+            let input = EvaluateJson { expression: "1 / 3".to_string(), mode: Mode::Decimal, scale: Some(500), ieee: false };
+            let err = evaluate(session.clone(), input, repos.clone(), Limits::default()).await.err().unwrap();
+            assert!(matches!(error(&err), Error::BadRequest(_)));
+
+            let listed = body_json(history(session, HistoryQuery::default(), repos).await.unwrap()).await;
+            let exact: Vec<_> = listed["history"].as_array().unwrap().iter().map(|row| row["exact_result"].clone()).collect();
+            assert_eq!(exact, ["1180591620717411303425", "1/3", "0.3"]);
+        }
+
+        #[tokio::test]
+        async fn non_finite_results_are_errors_unless_ieee() {
+            let repos = Repositories::memory();
+            let registry = Arc::new(Registry::builtin());
+            let session = new_session(&repos).await;
+            let err = calculate(session.clone(), operation(1.0, 0.0, 4), registry.clone(), repos.clone(), Limits::default()).await.err().unwrap();
+            assert_eq!(error(&err).code(), "division_by_zero");
+            let err = calculate(session.clone(), operation(1e300, 1e300, 3), registry.clone(), repos.clone(), Limits::default()).await.err().unwrap();
+            assert_eq!(error(&err).code(), "overflow");
+            let input = EvaluateJson { expression: "1e999".to_string(), mode: Mode::F64, scale: None, ieee: false };
+            let err = evaluate(session.clone(), input, repos.clone(), Limits::default()).await.err().unwrap();
+            assert_eq!(error(&err).code(), "overflow");
+
+            let input = CalculateJson { ieee: true, ..operation(-1.0, 0.0, 4) };
+            let reply = calculate(session.clone(), input, registry.clone(), repos.clone(), Limits::default()).await.unwrap();
+            assert_eq!(body_json(reply).await["result"], "-inf");
+            let input = CalculateJson { num1: Operand::Text("inf".to_string()), ieee: true, ..operation(0.0, 0.0, 3) };
+            let reply = calculate(session.clone(), input, registry.clone(), repos.clone(), Limits::default()).await.unwrap();
+            assert_eq!(body_json(reply).await["result"], "NaN");
+            let input = CalculateJson { num1: Operand::Text("inf".to_string()), ..operation(0.0, 0.0, 3) };
+            let err = calculate(session.clone(), input, registry.clone(), repos.clone(), Limits::default()).await.err().unwrap();
+            assert!(matches!(error(&err), Error::BadRequest(_)));
+
+            let listed = body_json(history(session, HistoryQuery::default(), repos).await.unwrap()).await;
+            let rows: Vec<_> = listed["history"].as_array().unwrap().iter().map(|row| (row["num1"].clone(), row["result"].clone())).collect();
+            assert_eq!(rows, [("inf".into(), "NaN".into()), (serde_json::json!(-1.0), "-inf".into())]);
+        }
+
+        #[tokio::test]
+        async fn history_pages_follow_the_cursor() {
+            let repos = Repositories::memory();
+            let session = new_session(&repos).await;
+            for num1 in 1..=5 {
+                let input = operation(num1 as f64, 2.0, 3);
+                calculate(session.clone(), input, Arc::new(Registry::builtin()), repos.clone(), Limits::default()).await.unwrap();
+            }
+
+            let query = HistoryQuery { limit: Some(2), sort: SortKey::Result, order: Order::Asc, ..Default::default() };
+            let mut results = Vec::new();
+            let mut cursor = None;
+            loop {
+                let query = HistoryQuery { cursor, ..query.clone() };
+                let page = body_json(history(session.clone(), query, repos.clone()).await.unwrap()).await;
+                results.extend(page["history"].as_array().unwrap().iter().map(|row| row["result"].as_f64().unwrap()));
+                match page["next_cursor"].as_str() {
+                    Some(next) => cursor = Some(next.to_string()),
+                    None => break,
+                }
+            }
+            assert_eq!(results, [2.0, 4.0, 6.0, 8.0, 10.0]);
+
+            let query = HistoryQuery { limit: Some(0), ..Default::default() };
+            let err = history(session, query, repos).await.err().unwrap();
+            assert!(matches!(error(&err), Error::BadRequest(_)));
+        }
+
+        #[tokio::test]
+        async fn export_streams_history_with_operator_names() {
+            let repos = Repositories::memory();
+            let registry = Arc::new(Registry::builtin());
+            let session = new_session(&repos).await;
+            for (num1, operator_id) in [(1.5, 1), (7.0, 4)] {
+                let input = operation(num1, 2.0, operator_id);
+                calculate(session.clone(), input, registry.clone(), repos.clone(), Limits::default()).await.unwrap();
+            }
+
+            let query = ExportQuery { format: ExportFormat::Tsv };
+            let response = export_history(session, query, repos, registry).await.unwrap().into_response();
+            assert_eq!(response.headers()["content-type"], "text/tab-separated-values; charset=utf-8");
+            assert!(response.headers()["content-disposition"].to_str().unwrap().starts_with("attachment; filename=\"history-"));
+            let body = warp::hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let lines: Vec<Vec<&str>> = std::str::from_utf8(&body).unwrap().lines().map(|line| line.split('\t').collect()).collect();
+            assert_eq!(lines.len(), 3);
+            assert_eq!(lines[0], ["id", "created_at", "expression", "num1", "operator", "num2", "result", "mode"]);
+            assert_eq!(lines[1][2..], ["1.5 + 2", "1.5", "Addition", "2.0", "3.5", "f64"]);
+            assert_eq!(lines[2][2..], ["7 / 2", "7.0", "Division", "2.0", "3.5", "f64"]);
+        }
+
+        #[tokio::test]
+        async fn deleted_entries_can_be_undone_by_their_owner_only() {
+            let repos = Repositories::memory();
+            let (session, stranger) = (new_session(&repos).await, new_session(&repos).await);
+            let undo_window = Duration::from_secs(60);
+            let input = operation(1.0, 2.0, 1);
+            calculate(session.clone(), input, Arc::new(Registry::builtin()), repos.clone(), Limits::default()).await.unwrap();
+            let listed = body_json(history(session.clone(), HistoryQuery::default(), repos.clone()).await.unwrap()).await;
+            let id = listed["history"][0]["id"].as_i64().unwrap() as i32;
+
+            let err = delete_history_entry(id, stranger, repos.clone(), undo_window).await.err().unwrap();
+            assert!(matches!(error(&err), Error::NotFound(_)));
+
+            let deleted = body_json(delete_history_entry(id, session.clone(), repos.clone(), undo_window).await.unwrap()).await;
+            assert_eq!(deleted["deleted"], 1);
+            let listed = body_json(history(session.clone(), HistoryQuery::default(), repos.clone()).await.unwrap()).await;
+            assert!(listed["history"].as_array().unwrap().is_empty());
+            assert!(delete_history_entry(id, session.clone(), repos.clone(), undo_window).await.is_err());
+
+            let restored = body_json(undo_delete_history(session.clone(), repos.clone(), undo_window).await.unwrap()).await;
+            assert_eq!(restored["restored"], 1);
+            let listed = body_json(history(session, HistoryQuery::default(), repos.clone()).await.unwrap()).await;
+            assert_eq!(listed["history"][0]["id"], id);
+            assert_eq!(repos.calculations.purge_deleted(Duration::ZERO).await.unwrap(), 0);
+        }
+
+        #[tokio::test]
+        async fn register_logs_in_and_rejects_taken_names() {
+            let repos = Repositories::memory();
+            let session = new_session(&repos).await;
+
+            let reply = register(session.clone(), credentials("ann", "secret"), Client::default(), repos.clone(), issuer(), passwords(), throttle()).await.unwrap();
+            assert!(repos.sessions.touch(&session.hash, SessionLifetimes::default()).await.unwrap().is_none());
+            let (_, session) = logged_in(&repos, reply).await;
+            assert!(session.is_auth);
+            assert_eq!(session.name, "ann");
+
+            let err = register(session, credentials("ann", "other"), Client::default(), repos, issuer(), passwords(), throttle()).await.err().unwrap();
+            assert!(matches!(error(&err), Error::Conflict(_)));
+        }
+
+        #[tokio::test]
+        async fn login_rejects_wrong_password() {
+            let repos = Repositories::memory();
+            let auth_hash = passwords().hash("secret").unwrap();
+            repos.users.create("ann", &auth_hash, "normise").await.unwrap().unwrap();
+            let session = new_session(&repos).await;
+
+            let err = login(session.clone(), credentials("ann", "wrong"), Client::default(), repos.clone(), issuer(), passwords(), throttle()).await.err().unwrap();
+            assert!(matches!(error(&err), Error::InvalidCredentials));
+
+            login(session, credentials("ann", "secret"), Client::default(), repos, issuer(), passwords(), throttle()).await.unwrap();
+        }
+
+        #[tokio::test]
+        async fn login_adopts_anonymous_history_unless_opted_out() {
+            let repos = Repositories::memory();
+            let registry = Arc::new(Registry::builtin());
+            let auth_hash = passwords().hash("secret").unwrap();
+            repos.users.create("ann", &auth_hash, "normise").await.unwrap().unwrap();
+            let (adopting, keeping) = (new_session(&repos).await, new_session(&repos).await);
+            for session in [&adopting, &keeping] {
+                let input = operation(1.0, 2.0, 1);
+                calculate(session.clone(), input, registry.clone(), repos.clone(), Limits::default()).await.unwrap();
+            }
+
+            let reply = login(adopting, credentials("ann", "secret"), Client::default(), repos.clone(), issuer(), passwords(), throttle()).await.unwrap();
+            let (reply, adopted) = logged_in(&repos, reply).await;
+            assert_eq!(reply["adopted"], 1);
+            let opt_out = TestLoginJson { adopt_history: false, ..credentials("ann", "secret") };
+            let reply = login(keeping, opt_out, Client::default(), repos.clone(), issuer(), passwords(), throttle()).await.unwrap();
+            let (reply, session) = logged_in(&repos, reply).await;
+            assert_eq!(reply["adopted"], 0);
+
+            let listed = body_json(history(session, HistoryQuery::default(), repos).await.unwrap()).await;
+            let sessions: Vec<_> = listed["history"].as_array().unwrap().iter().map(|row| row["session_id"].clone()).collect();
+            assert_eq!(sessions, [adopted.id]);
+        }
+
+        #[tokio::test]
+        async fn authorize_checks_login_and_role() {
+            let repos = Repositories::memory();
+            let session = new_session(&repos).await;
+            let err = authorize(repos.clone(), session.clone(), Permission::ViewUsers).await.err().unwrap();
+            assert!(matches!(error(&err), Error::NotLoggedIn));
+
+            let user_id = repos.users.create("ann", "unused", "normise").await.unwrap().unwrap();
+            let user = repos.users.find_by_id(user_id).await.unwrap().unwrap();
+            let session = log_in(&repos, &session, &user).await;
+            let err = authorize(repos.clone(), session.clone(), Permission::ViewUsers).await.err().unwrap();
+            assert!(matches!(error(&err), Error::Forbidden(Permission::ViewUsers)));
+
+            let admin_id = repos.users.create("root", "unused", "moderling").await.unwrap().unwrap();
+            let admin = repos.users.find_by_id(admin_id).await.unwrap().unwrap();
+            let session = log_in(&repos, &session, &admin).await;
+            assert_eq!(authorize(repos, session, Permission::ViewUsers).await.unwrap().name, "root");
+        }
+
+        #[tokio::test]
+        async fn revoked_sessions_are_not_found() {
+            let repos = Repositories::memory();
+            let user_id = repos.users.create("ann", "unused", "normise").await.unwrap().unwrap();
+            let user = repos.users.find_by_id(user_id).await.unwrap().unwrap();
+            let (first, second, other) = (new_session(&repos).await, new_session(&repos).await, new_session(&repos).await);
+            let (first, second) = (log_in(&repos, &first, &user).await, log_in(&repos, &second, &user).await);
+
+            let listed: Vec<i32> = repos.sessions.list_live(user_id, SessionLifetimes::default()).await.unwrap().iter().map(|session| session.id).collect();
+            assert_eq!(listed.len(), 2);
+            assert!(!repos.sessions.revoke_for_user(user_id, other.id).await.unwrap());
+
+            assert!(repos.sessions.revoke(&first.hash).await.unwrap());
+            assert!(!repos.sessions.revoke(&first.hash).await.unwrap());
+            assert!(!repos.sessions.revoke_for_user(user_id, first.id).await.unwrap());
+            assert_eq!(repos.sessions.list_live(user_id, SessionLifetimes::default()).await.unwrap().len(), 1);
+            assert!(repos.sessions.touch(&first.hash, SessionLifetimes::default()).await.unwrap().is_none());
+            assert_eq!(repos.sessions.revoke_all(user_id).await.unwrap(), 1);
+            assert!(repos.sessions.touch(&second.hash, SessionLifetimes::default()).await.unwrap().is_none());
+            assert!(repos.sessions.touch(&other.hash, SessionLifetimes::default()).await.unwrap().is_some());
+            // The two replaced at login and the two revoked since.
+            assert_eq!(repos.sessions.purge_expired(SessionLifetimes::default()).await.unwrap(), 4);
+            assert!(repos.sessions.touch(&other.hash, SessionLifetimes::default()).await.unwrap().is_some());
+        }
+
+        #[tokio::test]
+        async fn import_dry_run_changes_nothing() {
+            let repos = Repositories::memory();
+            let admin = User { id: 0, name: "root".to_string(), auth_hash: String::new(), role: "moderling".to_string() };
+            let auth_hash = passwords().hash("secret").unwrap();
+            let body = serde_json::json!({ "users": [{ "name": "ann", "auth_hash": auth_hash, "role": "normise" }] });
+            let query = ImportQuery { format: ImportFormat::Json, dry_run: true, ..ImportQuery::default() };
+
+            let report = body_json(import_users(admin, query, Bytes::from(body.to_string()), repos.clone()).await.unwrap()).await;
+            assert_eq!(report["created"], 1);
+            assert_eq!(report["applied"], false);
+            assert!(repos.users.list().await.unwrap().is_empty());
+        }
+    }
 }
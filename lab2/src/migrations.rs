@@ -0,0 +1,109 @@
+//! Versioned schema migrations, embedded into the binary.
+//!
+//! Applied versions are recorded in `schema_migrations`. To change the
+//! schema add a new `migrations/NNNN_name.sql` file and append it to
+//! `MIGRATIONS`; never edit a migration that has already shipped.
+
+use std::fmt;
+use rusqlite::{params, Connection, OptionalExtension};
+
+struct Migration {
+    version: u32,
+    name: &'static str,
+    sql: &'static str,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "initial", sql: include_str!("../migrations/0001_initial.sql") },
+    Migration { version: 2, name: "calculation_expressions", sql: include_str!("../migrations/0002_calculation_expressions.sql") },
+    Migration { version: 3, name: "session_timestamps", sql: include_str!("../migrations/0003_session_timestamps.sql") },
+    Migration { version: 4, name: "roles", sql: include_str!("../migrations/0004_roles.sql") },
+    Migration { version: 5, name: "hashed_session_tokens", sql: include_str!("../migrations/0005_hashed_session_tokens.sql") },
+    Migration { version: 6, name: "operator_registry", sql: include_str!("../migrations/0006_operator_registry.sql") },
+    Migration { version: 7, name: "calculation_history_index", sql: include_str!("../migrations/0007_calculation_history_index.sql") },
+    Migration { version: 8, name: "soft_deleted_calculations", sql: include_str!("../migrations/0008_soft_deleted_calculations.sql") },
+    Migration { version: 9, name: "numeric_modes", sql: include_str!("../migrations/0009_numeric_modes.sql") },
+    Migration { version: 10, name: "revoked_sessions", sql: include_str!("../migrations/0010_revoked_sessions.sql") },
+    Migration { version: 11, name: "session_clients", sql: include_str!("../migrations/0011_session_clients.sql") },
+    Migration { version: 12, name: "unique_user_names", sql: include_str!("../migrations/0012_unique_user_names.sql") },
+    Migration { version: 13, name: "detachable_session_history", sql: include_str!("../migrations/0013_detachable_session_history.sql") },
+];
+
+#[derive(Debug)]
+pub enum Error {
+    Sqlite(rusqlite::Error),
+    /// The database was migrated by a newer build than this one.
+    TooNew { database: u32, supported: u32 },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Sqlite(err) => write!(f, "migration failed: {err}"),
+            Error::TooNew { database, supported } => write!(
+                f,
+                "database schema version {database} is newer than the latest version {supported} supported by this binary"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Error {
+        Error::Sqlite(err)
+    }
+}
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+pub fn current_version(conn: &Connection) -> Result<u32, rusqlite::Error> {
+    conn.execute_batch(
+        "create table if not exists schema_migrations(
+            version integer primary key,
+            name string not null,
+            applied_at string not null default current_timestamp
+        );",
+    )?;
+    let version: Option<u32> = conn
+        .query_row("select max(version) from schema_migrations;", [], |row| row.get(0))
+        .optional()?
+        .flatten();
+    Ok(version.unwrap_or(0))
+}
+
+/// Brings the schema up to `latest_version()`, one transaction per migration.
+///
+/// Foreign keys are not enforced while migrations run: rebuilding a table
+/// copies its rows, and databases from before migrations hold rows that
+/// refer to deleted users.
+pub fn run(conn: &mut Connection) -> Result<u32, Error> {
+    let current = current_version(conn)?;
+    let supported = latest_version();
+    if current > supported {
+        return Err(Error::TooNew { database: current, supported });
+    }
+
+    conn.pragma_update(None, "foreign_keys", false)?;
+    let applied = apply(conn, current);
+    conn.pragma_update(None, "foreign_keys", true)?;
+    applied?;
+    Ok(supported)
+}
+
+fn apply(conn: &mut Connection, current: u32) -> Result<(), Error> {
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration.sql)?;
+        tx.execute(
+            "insert into schema_migrations(version, name) values (?1, ?2);",
+            params![migration.version, migration.name],
+        )?;
+        tx.commit()?;
+        log::info!("applied migration {:04}_{}", migration.version, migration.name);
+    }
+    Ok(())
+}
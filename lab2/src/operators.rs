@@ -0,0 +1,58 @@
+//! Operator registry behind `/api/calculate` and `/api/operators`.
+//!
+//! Rows of the `operators` table are loaded once at startup and checked by
+//! `calc_core::operators::Operator::new`. Rows added to the table take
+//! effect after a restart.
+
+use std::fmt;
+
+use rusqlite::Connection;
+
+pub use calc_core::operators::{Operator, Registry};
+
+#[derive(Debug)]
+pub enum Error {
+    Sqlite(rusqlite::Error),
+    /// A row the expression engine cannot implement.
+    Invalid { id: i32, message: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Sqlite(err) => write!(f, "cannot load operators: {err}"),
+            Error::Invalid { id, message } => write!(f, "operator {id}: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Error {
+        Error::Sqlite(err)
+    }
+}
+
+pub fn load(db: &Connection) -> Result<Registry, Error> {
+    let mut stmt = db.prepare("select id, name, symbol, arity, precedence from operators order by id;")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i32>(0)?,
+            row.get::<_, Option<String>>(1)?,
+            row.get::<_, Option<String>>(2)?,
+            row.get::<_, i64>(3)?,
+            row.get::<_, i64>(4)?,
+        ))
+    })?;
+
+    let mut operators = Vec::new();
+    for row in rows {
+        let (id, name, symbol, arity, precedence) = row?;
+        let operator = Operator::new(id, name, &symbol.unwrap_or_default(), arity, precedence)
+            .map_err(|message| Error::Invalid { id, message })?;
+        operators.push(operator);
+    }
+
+    Ok(operators.into_iter().collect())
+}
@@ -0,0 +1,148 @@
+//! Password hashing for `users.auth_hash`.
+//!
+//! New hashes are Argon2id PHC strings with a random per-user salt.
+//! Rows written before hashing existed hold the literal `name:password`;
+//! they still verify, but are reported as needing a rehash so the caller
+//! can upgrade them on the next successful login.
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{self, PasswordHash, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use subtle::ConstantTimeEq;
+
+/// Argon2id cost parameters. Defaults follow the OWASP recommendation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashCost {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for HashCost {
+    fn default() -> HashCost {
+        HashCost {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    Invalid,
+    Valid,
+    /// Password matched, but the stored hash is legacy plaintext or uses
+    /// other cost parameters than the current ones.
+    ValidNeedsRehash,
+}
+
+#[derive(Debug, Clone)]
+pub struct Passwords {
+    params: Params,
+    /// Checked when there is no user, see `verify_unknown`.
+    dummy_hash: String,
+}
+
+impl Passwords {
+    pub fn new(cost: HashCost) -> Result<Passwords, argon2::Error> {
+        let params = Params::new(cost.memory_kib, cost.iterations, cost.parallelism, None)?;
+        let mut passwords = Passwords { params, dummy_hash: String::new() };
+        passwords.dummy_hash = passwords.hash("").expect("hashing with validated parameters");
+        Ok(passwords)
+    }
+
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+
+    pub fn hash(&self, password: &str) -> Result<String, password_hash::Error> {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = argon2::PasswordHasher::hash_password(&self.argon2(), password.as_bytes(), &salt)?;
+        Ok(hash.to_string())
+    }
+
+    pub fn verify(&self, name: &str, password: &str, auth_hash: &str) -> Verification {
+        let parsed = match PasswordHash::new(auth_hash) {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                // Spend as long as an Argon2 check would, so timing does not
+                // reveal which accounts still have a legacy row.
+                self.verify_unknown(password);
+                return verify_legacy(name, password, auth_hash);
+            }
+        };
+
+        if self.argon2().verify_password(password.as_bytes(), &parsed).is_err() {
+            return Verification::Invalid;
+        }
+
+        let up_to_date = parsed.algorithm == Algorithm::Argon2id.ident()
+            && Params::try_from(&parsed).is_ok_and(|params| {
+                params.m_cost() == self.params.m_cost()
+                    && params.t_cost() == self.params.t_cost()
+                    && params.p_cost() == self.params.p_cost()
+            });
+        if up_to_date {
+            Verification::Valid
+        } else {
+            Verification::ValidNeedsRehash
+        }
+    }
+
+    /// Takes as long as `verify` against a current hash and fails, so a
+    /// login for a name that does not exist cannot be told apart by timing.
+    pub fn verify_unknown(&self, password: &str) -> Verification {
+        let _ = self.verify("", password, &self.dummy_hash);
+        Verification::Invalid
+    }
+}
+
+fn verify_legacy(name: &str, password: &str, auth_hash: &str) -> Verification {
+    let expected = format!("{name}:{password}");
+    if bool::from(expected.as_bytes().ct_eq(auth_hash.as_bytes())) {
+        Verification::ValidNeedsRehash
+    } else {
+        Verification::Invalid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn passwords(iterations: u32) -> Passwords {
+        Passwords::new(HashCost { memory_kib: 8, iterations, parallelism: 1 }).unwrap()
+    }
+
+    #[test]
+    fn verifies_current_hashes() {
+        let passwords = passwords(1);
+        let hash = passwords.hash("secret").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert_eq!(passwords.verify("ann", "secret", &hash), Verification::Valid);
+        assert_eq!(passwords.verify("ann", "wrong", &hash), Verification::Invalid);
+    }
+
+    #[test]
+    fn asks_to_rehash_when_the_cost_changed() {
+        let hash = passwords(1).hash("secret").unwrap();
+        assert_eq!(passwords(2).verify("ann", "secret", &hash), Verification::ValidNeedsRehash);
+        assert_eq!(passwords(2).verify("ann", "wrong", &hash), Verification::Invalid);
+    }
+
+    #[test]
+    fn upgrades_legacy_rows() {
+        let passwords = passwords(1);
+        assert_eq!(passwords.verify("ann", "secret", "ann:secret"), Verification::ValidNeedsRehash);
+        assert_eq!(passwords.verify("ann", "wrong", "ann:secret"), Verification::Invalid);
+        assert_eq!(passwords.verify("bob", "secret", "ann:secret"), Verification::Invalid);
+    }
+
+    #[test]
+    fn unknown_users_never_verify() {
+        let passwords = passwords(1);
+        assert_eq!(passwords.verify_unknown(""), Verification::Invalid);
+        assert_eq!(passwords.verify_unknown("secret"), Verification::Invalid);
+    }
+}
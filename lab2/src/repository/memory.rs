@@ -0,0 +1,366 @@
+//! In-memory repositories for handler tests. Seeded with the same roles as
+//! the SQLite migrations; everything else starts empty.
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+
+use super::{CalculationRepository, NewCalculation, Owner, Result, SessionRepository, UserRepository, TOUCH_INTERVAL};
+use crate::history::{HistoryFilter, Page};
+use crate::import::{self, Change, ImportQuery, ImportReport, ImportUser};
+use crate::models::{Calculation, Client, Permission, Role, Session, SessionLifetimes, User};
+
+#[derive(Debug)]
+struct State {
+    users: Vec<User>,
+    sessions: Vec<Session>,
+    calculations: Vec<Calculation>,
+    /// `deleted_at` of soft-deleted calculations, by id.
+    deleted: HashMap<i32, i64>,
+    /// Ids of logged-out sessions.
+    revoked: HashSet<i32>,
+    roles: HashMap<String, HashSet<Permission>>,
+    next_id: i32,
+}
+
+impl State {
+    fn next_id(&mut self) -> i32 {
+        self.next_id += 1;
+        self.next_id
+    }
+}
+
+impl Default for State {
+    fn default() -> State {
+        let moderling = [Permission::ViewUsers, Permission::DeleteUsers, Permission::ExportUsers, Permission::ImportUsers];
+        State {
+            users: Vec::new(),
+            sessions: Vec::new(),
+            calculations: Vec::new(),
+            deleted: HashMap::new(),
+            revoked: HashSet::new(),
+            roles: HashMap::from([
+                ("moderling".to_string(), moderling.into_iter().collect()),
+                ("normise".to_string(), HashSet::new()),
+            ]),
+            next_id: 0,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    state: Mutex<State>,
+}
+
+impl MemoryStore {
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
+fn is_owned_by(calculation: &Calculation, owner: Owner) -> bool {
+    match owner {
+        Owner::User(user_id) => calculation.user_id == Some(user_id),
+        Owner::Session(session_id) => calculation.session_id == Some(session_id),
+    }
+}
+
+fn is_live(session: &Session, lifetimes: SessionLifetimes, now: i64) -> bool {
+    session.created_at > now - lifetimes.absolute.as_secs() as i64
+        && session.last_seen_at > now - lifetimes.idle.as_secs() as i64
+}
+
+#[async_trait]
+impl UserRepository for MemoryStore {
+    async fn list(&self) -> Result<Vec<User>> {
+        Ok(self.state().users.clone())
+    }
+
+    async fn find_by_id(&self, id: i32) -> Result<Option<User>> {
+        Ok(self.state().users.iter().find(|user| user.id == id).cloned())
+    }
+
+    async fn find_by_name(&self, name: &str) -> Result<Option<User>> {
+        Ok(self.state().users.iter().find(|user| user.name == name).cloned())
+    }
+
+    async fn create(&self, name: &str, auth_hash: &str, role: &str) -> Result<Option<i32>> {
+        let mut state = self.state();
+        if state.users.iter().any(|user| user.name == name) {
+            return Ok(None);
+        }
+        let id = state.next_id();
+        state.users.push(User { id, name: name.to_owned(), auth_hash: auth_hash.to_owned(), role: role.to_owned() });
+        Ok(Some(id))
+    }
+
+    async fn set_auth_hash(&self, id: i32, auth_hash: &str) -> Result<()> {
+        if let Some(user) = self.state().users.iter_mut().find(|user| user.id == id) {
+            user.auth_hash = auth_hash.to_owned();
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, id: i32) -> Result<bool> {
+        let mut state = self.state();
+        let before = state.users.len();
+        state.users.retain(|user| user.id != id);
+        state.calculations.retain(|calculation| calculation.user_id != Some(id));
+        let State { sessions, revoked, .. } = &mut *state;
+        for session in sessions.iter_mut().filter(|session| session.user_id == Some(id)) {
+            session.is_auth = false;
+            session.user_id = None;
+            revoked.insert(session.id);
+        }
+        Ok(state.users.len() < before)
+    }
+
+    async fn role(&self, name: &str) -> Result<Role> {
+        let permissions = self.state().roles.get(name).cloned().unwrap_or_default();
+        Ok(Role { name: name.to_owned(), permissions })
+    }
+
+    async fn import(&self, records: Vec<ImportUser>, query: ImportQuery) -> Result<ImportReport> {
+        let mut state = self.state();
+        let roles = state.roles.keys().cloned().collect();
+        let (mut report, changes) = import::plan(&records, &roles, &query, |name| {
+            Ok::<_, crate::db::Error>(state.users.iter().find(|user| user.name == name).map(|user| user.id))
+        })?;
+        if !report.should_apply() {
+            return Ok(report);
+        }
+
+        for change in changes {
+            match change {
+                Change::Create(record) => {
+                    let id = state.next_id();
+                    state.users.push(User { id, name: record.name.clone(), auth_hash: record.auth_hash.clone(), role: record.role.clone() });
+                },
+                Change::Update(id, record) => {
+                    let Some(user) = state.users.iter_mut().find(|user| user.id == id) else {
+                        continue;
+                    };
+                    if user.auth_hash == record.auth_hash && user.role == record.role {
+                        continue;
+                    }
+                    user.auth_hash = record.auth_hash.clone();
+                    user.role = record.role.clone();
+                    let State { sessions, revoked, .. } = &mut *state;
+                    revoked.extend(sessions.iter().filter(|session| session.user_id == Some(id)).map(|session| session.id));
+                },
+            }
+        }
+        report.applied = true;
+        Ok(report)
+    }
+}
+
+#[async_trait]
+impl SessionRepository for MemoryStore {
+    async fn create(&self, hash: &str, name: &str) -> Result<()> {
+        let mut state = self.state();
+        let id = state.next_id();
+        let now = now();
+        state.sessions.push(Session {
+            id,
+            hash: hash.to_owned(),
+            is_auth: false,
+            user_id: None,
+            name: name.to_owned(),
+            created_at: now,
+            last_seen_at: now,
+            user_agent: None,
+            ip: None,
+        });
+        Ok(())
+    }
+
+    async fn touch(&self, hash: &str, lifetimes: SessionLifetimes) -> Result<Option<Session>> {
+        let now = now();
+        let mut state = self.state();
+        let State { sessions, revoked, .. } = &mut *state;
+        let Some(session) = sessions.iter_mut().find(|session| session.hash == hash) else {
+            return Ok(None);
+        };
+        if revoked.contains(&session.id) || !is_live(session, lifetimes, now) {
+            return Ok(None);
+        }
+        if now - session.last_seen_at >= TOUCH_INTERVAL.as_secs() as i64 {
+            session.last_seen_at = now;
+        }
+        Ok(Some(session.clone()))
+    }
+
+    async fn log_in(&self, hash: &str, new_hash: &str, user: &User, adopt_history: bool, client: &Client) -> Result<usize> {
+        let now = now();
+        let mut state = self.state();
+        let old_id = state.sessions.iter().find(|session| session.hash == hash).map(|session| session.id);
+        let id = state.next_id();
+        state.sessions.push(Session {
+            id,
+            hash: new_hash.to_owned(),
+            is_auth: true,
+            user_id: Some(user.id),
+            name: user.name.clone(),
+            created_at: now,
+            last_seen_at: now,
+            user_agent: client.user_agent.clone(),
+            ip: client.ip.clone(),
+        });
+        let Some(old_id) = old_id else {
+            return Ok(0);
+        };
+        state.revoked.insert(old_id);
+
+        if !adopt_history {
+            return Ok(0);
+        }
+        let mut adopted = 0;
+        for calculation in state.calculations.iter_mut().filter(|c| c.session_id == Some(old_id) && c.user_id.is_none()) {
+            calculation.session_id = Some(id);
+            calculation.user_id = Some(user.id);
+            adopted += 1;
+        }
+        Ok(adopted)
+    }
+
+    async fn revoke(&self, hash: &str) -> Result<bool> {
+        let mut state = self.state();
+        let Some(id) = state.sessions.iter().find(|session| session.hash == hash).map(|session| session.id) else {
+            return Ok(false);
+        };
+        Ok(state.revoked.insert(id))
+    }
+
+    async fn revoke_all(&self, user_id: i32) -> Result<usize> {
+        let mut state = self.state();
+        let State { sessions, revoked, .. } = &mut *state;
+        Ok(sessions.iter()
+            .filter(|session| session.user_id == Some(user_id))
+            .filter(|session| revoked.insert(session.id))
+            .count())
+    }
+
+    async fn revoke_for_user(&self, user_id: i32, id: i32) -> Result<bool> {
+        let mut state = self.state();
+        if !state.sessions.iter().any(|session| session.id == id && session.user_id == Some(user_id)) {
+            return Ok(false);
+        }
+        Ok(state.revoked.insert(id))
+    }
+
+    async fn list_live(&self, user_id: i32, lifetimes: SessionLifetimes) -> Result<Vec<Session>> {
+        let now = now();
+        let state = self.state();
+        let mut sessions: Vec<Session> = state.sessions.iter()
+            .filter(|session| session.user_id == Some(user_id) && !state.revoked.contains(&session.id) && is_live(session, lifetimes, now))
+            .cloned()
+            .collect();
+        sessions.sort_by_key(|session| std::cmp::Reverse((session.last_seen_at, session.id)));
+        Ok(sessions)
+    }
+
+    async fn purge_expired(&self, lifetimes: SessionLifetimes) -> Result<usize> {
+        let now = now();
+        let mut state = self.state();
+        let dead: HashSet<i32> = state.sessions.iter()
+            .filter(|session| state.revoked.contains(&session.id) || !is_live(session, lifetimes, now))
+            .map(|session| session.id)
+            .collect();
+        let is_dead = |session_id: Option<i32>| session_id.is_some_and(|id| dead.contains(&id));
+        state.calculations.retain(|calculation| calculation.user_id.is_some() || !is_dead(calculation.session_id));
+        for calculation in state.calculations.iter_mut().filter(|calculation| is_dead(calculation.session_id)) {
+            calculation.session_id = None;
+        }
+        state.sessions.retain(|session| !dead.contains(&session.id));
+        state.revoked.retain(|id| !dead.contains(id));
+        Ok(dead.len())
+    }
+}
+
+#[async_trait]
+impl CalculationRepository for MemoryStore {
+    async fn insert(&self, calculation: NewCalculation) -> Result<i32> {
+        let mut state = self.state();
+        let id = state.next_id();
+        state.calculations.push(Calculation {
+            id,
+            num1: calculation.num1,
+            num2: calculation.num2,
+            operator_id: calculation.operator_id,
+            result: calculation.result,
+            session_id: Some(calculation.session_id),
+            user_id: calculation.user_id,
+            expression: calculation.expression,
+            created_at: now(),
+            mode: calculation.mode,
+            exact_result: calculation.exact_result,
+        });
+        Ok(id)
+    }
+
+    async fn page(&self, owner: Owner, filter: &HistoryFilter) -> Result<Page> {
+        let state = self.state();
+        let mut rows: Vec<Calculation> = state.calculations.iter()
+            .filter(|c| is_owned_by(c, owner) && !state.deleted.contains_key(&c.id))
+            .filter(|c| filter.matches(c) && filter.is_after_cursor(c))
+            .cloned()
+            .collect();
+        rows.sort_by(|a, b| filter.compare(a, b));
+        rows.truncate(filter.limit as usize + 1);
+        Ok(filter.page(rows))
+    }
+
+    async fn delete(&self, owner: Owner, id: i32) -> Result<bool> {
+        let mut state = self.state();
+        let owned = state.calculations.iter().any(|c| c.id == id && is_owned_by(c, owner));
+        if !owned || state.deleted.contains_key(&id) {
+            return Ok(false);
+        }
+        state.deleted.insert(id, now());
+        Ok(true)
+    }
+
+    async fn delete_all(&self, owner: Owner) -> Result<usize> {
+        let now = now();
+        let mut state = self.state();
+        let State { calculations, deleted, .. } = &mut *state;
+        let mut count = 0;
+        for calculation in calculations.iter().filter(|c| is_owned_by(c, owner)) {
+            if let Entry::Vacant(entry) = deleted.entry(calculation.id) {
+                entry.insert(now);
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    async fn restore(&self, owner: Owner, undo_window: Duration) -> Result<usize> {
+        let since = now() - undo_window.as_secs() as i64;
+        let mut state = self.state();
+        let State { calculations, deleted, .. } = &mut *state;
+        let before = deleted.len();
+        deleted.retain(|id, deleted_at| {
+            *deleted_at <= since || !calculations.iter().any(|c| c.id == *id && is_owned_by(c, owner))
+        });
+        Ok(before - deleted.len())
+    }
+
+    async fn purge_deleted(&self, undo_window: Duration) -> Result<usize> {
+        let since = now() - undo_window.as_secs() as i64;
+        let mut state = self.state();
+        let State { calculations, deleted, .. } = &mut *state;
+        let before = calculations.len();
+        calculations.retain(|c| deleted.get(&c.id).is_none_or(|deleted_at| *deleted_at > since));
+        deleted.retain(|_, deleted_at| *deleted_at > since);
+        Ok(before - calculations.len())
+    }
+}
@@ -0,0 +1,144 @@
+//! Storage behind the HTTP handlers.
+//!
+//! Handlers only see these traits, bundled in `Repositories`. `sqlite` is
+//! what the server runs on; `memory` keeps everything in a `Vec` so
+//! handlers can be tested without a database file.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use calc_core::numeric::Mode;
+
+use crate::db::{self, Database};
+use crate::history::{HistoryFilter, Page};
+use crate::import::{ImportQuery, ImportReport, ImportUser};
+use crate::models::{Client, Role, Session, SessionLifetimes, User};
+
+#[cfg(test)]
+pub mod memory;
+pub mod sqlite;
+
+pub type Result<T> = std::result::Result<T, db::Error>;
+
+/// Whose history a query is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Owner {
+    User(i32),
+    /// An anonymous session; only its own calculations.
+    Session(i32),
+}
+
+/// A calculation about to be stored; the id and time are assigned on insert.
+#[derive(Debug, Clone)]
+pub struct NewCalculation {
+    pub num1: Option<f64>,
+    pub num2: Option<f64>,
+    pub operator_id: Option<i32>,
+    pub result: f64,
+    pub session_id: i32,
+    pub user_id: Option<i32>,
+    pub expression: Option<String>,
+    pub mode: Mode,
+    pub exact_result: Option<String>,
+}
+
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    async fn list(&self) -> Result<Vec<User>>;
+    async fn find_by_id(&self, id: i32) -> Result<Option<User>>;
+    async fn find_by_name(&self, name: &str) -> Result<Option<User>>;
+    /// Returns None when the name is taken.
+    async fn create(&self, name: &str, auth_hash: &str, role: &str) -> Result<Option<i32>>;
+    async fn set_auth_hash(&self, id: i32, auth_hash: &str) -> Result<()>;
+    /// Also removes the user's calculations and logs out their sessions.
+    /// Returns false when there was no such user.
+    async fn delete(&self, id: i32) -> Result<bool>;
+    /// The role with its permissions; unknown roles have none.
+    async fn role(&self, name: &str) -> Result<Role>;
+    /// Runs `import::plan` and applies it atomically when it should be.
+    async fn import(&self, records: Vec<ImportUser>, query: ImportQuery) -> Result<ImportReport>;
+}
+
+/// How stale `last_seen_at` may get before `touch` writes it. Idle
+/// timeouts are measured to within this much.
+pub const TOUCH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[async_trait]
+pub trait SessionRepository: Send + Sync {
+    /// Stores a new anonymous session under the hash of its token.
+    async fn create(&self, hash: &str, name: &str) -> Result<()>;
+    /// Finds a live session and marks it as seen. Sessions past their
+    /// absolute lifetime or idle timeout are not returned. `last_seen_at`
+    /// is only written once it is `TOUCH_INTERVAL` old, so most requests
+    /// only read.
+    async fn touch(&self, hash: &str, lifetimes: SessionLifetimes) -> Result<Option<Session>>;
+    /// Replaces session `hash` with a new one of `user`, stored under
+    /// `new_hash`, and revokes it: a cookie planted before login is no use
+    /// after it. With `adopt_history`, the old session's anonymous
+    /// calculations move to the new session and into the account in the
+    /// same transaction; calculations already owned by an account are never
+    /// touched. Returns how many were adopted. `client` is kept for
+    /// `list_live`.
+    async fn log_in(&self, hash: &str, new_hash: &str, user: &User, adopt_history: bool, client: &Client) -> Result<usize>;
+    /// The live sessions of `user_id`, most recently active first.
+    async fn list_live(&self, user_id: i32, lifetimes: SessionLifetimes) -> Result<Vec<Session>>;
+    /// Ends the session so `touch` never returns it again. Returns false
+    /// when it was already revoked or never existed.
+    async fn revoke(&self, hash: &str) -> Result<bool>;
+    /// Ends every session of `user_id`. Returns how many were revoked.
+    async fn revoke_all(&self, user_id: i32) -> Result<usize>;
+    /// Ends session `id` if it belongs to `user_id`. Returns false when it
+    /// does not, or was already revoked.
+    async fn revoke_for_user(&self, user_id: i32, id: i32) -> Result<bool>;
+    /// Deletes every revoked or expired session, logged in or not, with the
+    /// anonymous calculations that only it could see. Calculations that
+    /// belong to an account stay, with their `session_id` cleared.
+    async fn purge_expired(&self, lifetimes: SessionLifetimes) -> Result<usize>;
+}
+
+#[async_trait]
+pub trait CalculationRepository: Send + Sync {
+    async fn insert(&self, calculation: NewCalculation) -> Result<i32>;
+    /// One page of `owner`'s history, filtered and sorted by `filter`.
+    /// Deleted calculations are left out.
+    async fn page(&self, owner: Owner, filter: &HistoryFilter) -> Result<Page>;
+    /// Marks one of `owner`'s calculations as deleted. Returns false when
+    /// `owner` has no such calculation in history.
+    async fn delete(&self, owner: Owner, id: i32) -> Result<bool>;
+    /// Marks all of `owner`'s calculations as deleted.
+    async fn delete_all(&self, owner: Owner) -> Result<usize>;
+    /// Brings back what `owner` deleted within the last `undo_window`.
+    async fn restore(&self, owner: Owner, undo_window: Duration) -> Result<usize>;
+    /// Permanently removes calculations deleted longer than `undo_window` ago.
+    async fn purge_deleted(&self, undo_window: Duration) -> Result<usize>;
+}
+
+#[derive(Clone)]
+pub struct Repositories {
+    pub users: Arc<dyn UserRepository>,
+    pub sessions: Arc<dyn SessionRepository>,
+    pub calculations: Arc<dyn CalculationRepository>,
+}
+
+impl Repositories {
+    pub fn sqlite(db: Database) -> Repositories {
+        let store = Arc::new(sqlite::SqliteStore::new(db));
+        Repositories {
+            users: store.clone(),
+            sessions: store.clone(),
+            calculations: store,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn memory() -> Repositories {
+        let store = Arc::new(memory::MemoryStore::default());
+        Repositories {
+            users: store.clone(),
+            sessions: store.clone(),
+            calculations: store,
+        }
+    }
+}
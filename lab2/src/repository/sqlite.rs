@@ -0,0 +1,406 @@
+//! Repositories on top of the pooled SQLite database.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rusqlite::types::{Type, Value, ValueRef};
+use rusqlite::{params, params_from_iter, OptionalExtension, Row};
+
+use super::{CalculationRepository, NewCalculation, Owner, Result, SessionRepository, UserRepository, TOUCH_INTERVAL};
+use crate::db::Database;
+use crate::history::{Cursor, HistoryFilter, Order, Page};
+use crate::import::{self, Change, ImportQuery, ImportReport, ImportUser};
+use crate::models::{Calculation, Client, Permission, Role, Session, SessionLifetimes, User};
+
+const USER_COLUMNS: &str = "id, name, auth_hash, role";
+const SESSION_COLUMNS: &str = "id, hash, is_auth, user_id, name, created_at, last_seen_at, user_agent, ip";
+const CALCULATION_COLUMNS: &str = "id, num1, num2, operator_id, result, session_id, user_id, expression, created_at, mode, exact_result";
+
+fn user_from_row(row: &Row) -> rusqlite::Result<User> {
+    Ok(User {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        auth_hash: row.get(2)?,
+        role: row.get(3)?,
+    })
+}
+
+fn session_from_row(row: &Row) -> rusqlite::Result<Session> {
+    Ok(Session {
+        id: row.get(0)?,
+        hash: row.get(1)?,
+        is_auth: row.get(2)?,
+        user_id: row.get(3)?,
+        name: row.get(4)?,
+        created_at: row.get(5)?,
+        last_seen_at: row.get(6)?,
+        user_agent: row.get(7)?,
+        ip: row.get(8)?,
+    })
+}
+
+/// SQLite stores a NaN double as NULL, so NaN goes in as the text `NaN`,
+/// which also sorts after every number, as in `f64::total_cmp`.
+fn real(value: f64) -> Value {
+    if value.is_nan() {
+        Value::Text("NaN".to_string())
+    } else {
+        Value::Real(value)
+    }
+}
+
+fn real_from_row(row: &Row, index: usize) -> rusqlite::Result<Option<f64>> {
+    match row.get_ref(index)? {
+        ValueRef::Text(b"NaN") => Ok(Some(f64::NAN)),
+        _ => row.get(index),
+    }
+}
+
+fn calculation_from_row(row: &Row) -> rusqlite::Result<Calculation> {
+    Ok(Calculation {
+        id: row.get(0)?,
+        num1: real_from_row(row, 1)?,
+        num2: real_from_row(row, 2)?,
+        operator_id: row.get(3)?,
+        result: real_from_row(row, 4)?.ok_or(rusqlite::Error::InvalidColumnType(4, "result".to_string(), Type::Null))?,
+        session_id: row.get(5)?,
+        user_id: row.get(6)?,
+        expression: row.get(7)?,
+        created_at: row.get(8)?,
+        mode: row.get::<_, String>(9)?.parse()
+            .map_err(|massage: String| rusqlite::Error::FromSqlConversionFailure(9, Type::Text, massage.into()))?,
+        exact_result: row.get(10)?,
+    })
+}
+
+fn owner_condition(owner: Owner) -> (&'static str, i32) {
+    match owner {
+        Owner::User(user_id) => ("user_id = ?", user_id),
+        Owner::Session(session_id) => ("session_id = ?", session_id),
+    }
+}
+
+pub struct SqliteStore {
+    db: Database,
+}
+
+impl SqliteStore {
+    pub fn new(db: Database) -> SqliteStore {
+        SqliteStore { db }
+    }
+}
+
+#[async_trait]
+impl UserRepository for SqliteStore {
+    async fn list(&self) -> Result<Vec<User>> {
+        self.db.read(|db| {
+            let mut stmt = db.prepare(&format!("select {USER_COLUMNS} from users;"))?;
+            let users = stmt.query_map([], user_from_row)?;
+            users.collect()
+        }).await
+    }
+
+    async fn find_by_id(&self, id: i32) -> Result<Option<User>> {
+        self.db.read(move |db| {
+            db.query_row(&format!("select {USER_COLUMNS} from users where id = ?1;"), [id], user_from_row).optional()
+        }).await
+    }
+
+    async fn find_by_name(&self, name: &str) -> Result<Option<User>> {
+        let name = name.to_owned();
+        self.db.read(move |db| {
+            db.query_row(&format!("select {USER_COLUMNS} from users where name = ?1;"), [name], user_from_row).optional()
+        }).await
+    }
+
+    async fn create(&self, name: &str, auth_hash: &str, role: &str) -> Result<Option<i32>> {
+        let (name, auth_hash, role) = (name.to_owned(), auth_hash.to_owned(), role.to_owned());
+        self.db.write(move |db| {
+            match db.execute("insert into users(name, auth_hash, role) values(?1, ?2, ?3);", [&name, &auth_hash, &role]) {
+                Ok(_) => Ok(Some(db.last_insert_rowid() as i32)),
+                // `users_name` is the only constraint an insert can break.
+                Err(rusqlite::Error::SqliteFailure(err, _)) if err.code == rusqlite::ErrorCode::ConstraintViolation => Ok(None),
+                Err(err) => Err(err),
+            }
+        }).await
+    }
+
+    async fn set_auth_hash(&self, id: i32, auth_hash: &str) -> Result<()> {
+        let auth_hash = auth_hash.to_owned();
+        self.db.write(move |db| {
+            db.execute("update users set auth_hash = ?1 where id = ?2;", params![&auth_hash, id])?;
+            Ok(())
+        }).await
+    }
+
+    async fn delete(&self, id: i32) -> Result<bool> {
+        self.db.write(move |db| {
+            let tx = db.transaction()?;
+            tx.execute("delete from calculations where user_id = ?1;", [id])?;
+            // Revoked, so a live cookie of the account does not carry on as
+            // an anonymous session.
+            tx.execute("update sessions set is_auth = false, user_id = null, revoked_at = coalesce(revoked_at, unixepoch()) where user_id = ?1;", [id])?;
+            let deleted = tx.execute("delete from users where id = ?1;", [id])? > 0;
+            tx.commit()?;
+            Ok(deleted)
+        }).await
+    }
+
+    async fn role(&self, name: &str) -> Result<Role> {
+        let role_name = name.to_owned();
+        let permissions = self.db.read(move |db| {
+            let mut stmt = db.prepare("select permission from role_permissions where role = ?1;")?;
+            let permissions = stmt.query_map([role_name], |row| row.get::<_, String>(0))?;
+            permissions.collect::<rusqlite::Result<Vec<String>>>()
+        }).await?;
+
+        let mut role = Role { name: name.to_owned(), permissions: HashSet::new() };
+        for permission in permissions {
+            match permission.parse::<Permission>() {
+                Ok(permission) => { role.permissions.insert(permission); },
+                Err(massage) => log::warn!("role {name}: {massage}"),
+            }
+        }
+        Ok(role)
+    }
+
+    async fn import(&self, records: Vec<ImportUser>, query: ImportQuery) -> Result<ImportReport> {
+        self.db.write(move |db| {
+            let tx = db.transaction()?;
+
+            let roles = {
+                let mut stmt = tx.prepare("select name from roles;")?;
+                let roles = stmt.query_map([], |row| row.get(0))?;
+                roles.collect::<rusqlite::Result<HashSet<String>>>()?
+            };
+
+            let (mut report, changes) = import::plan(&records, &roles, &query, |name| {
+                tx.query_row("select id from users where name = ?1 order by id limit 1;", [name], |row| row.get(0)).optional()
+            })?;
+            if !report.should_apply() {
+                // Dropping `tx` rolls back.
+                return Ok(report);
+            }
+
+            for change in changes {
+                match change {
+                    Change::Create(user) => tx.execute("insert into users(name, auth_hash, role) values(?1, ?2, ?3);",
+                        [&user.name, &user.auth_hash, &user.role])?,
+                    Change::Update(id, user) => {
+                        let changed = tx.execute("update users set auth_hash = ?1, role = ?2 where id = ?3 and (auth_hash is not ?1 or role is not ?2);",
+                            params![&user.auth_hash, &user.role, id])?;
+                        // A reset password or a new role must not leave
+                        // whoever holds an old session logged in.
+                        if changed > 0 {
+                            tx.execute("update sessions set revoked_at = unixepoch() where user_id = ?1 and revoked_at is null;", [id])?;
+                        }
+                        changed
+                    },
+                };
+            }
+            tx.commit()?;
+            report.applied = true;
+            Ok(report)
+        }).await
+    }
+}
+
+#[async_trait]
+impl SessionRepository for SqliteStore {
+    async fn create(&self, hash: &str, name: &str) -> Result<()> {
+        let (hash, name) = (hash.to_owned(), name.to_owned());
+        self.db.write(move |db| {
+            db.execute("insert into sessions (hash, is_auth, name, created_at, last_seen_at) values (?1, ?2, ?3, unixepoch(), unixepoch());",
+                params![&hash, false, &name])?;
+            Ok(())
+        }).await
+    }
+
+    async fn touch(&self, hash: &str, lifetimes: SessionLifetimes) -> Result<Option<Session>> {
+        let hash = hash.to_owned();
+        let session = self.db.read(move |db| {
+            db.query_row(&format!("select {SESSION_COLUMNS}, unixepoch() from sessions where hash = ?1 and revoked_at is null and created_at > unixepoch() - ?2 and last_seen_at > unixepoch() - ?3;"), params![
+                &hash,
+                lifetimes.absolute.as_secs(),
+                lifetimes.idle.as_secs(),
+            ], |row| Ok((session_from_row(row)?, row.get::<_, i64>(9)?))).optional()
+        }).await?;
+        let Some((mut session, now)) = session else {
+            return Ok(None);
+        };
+
+        if now - session.last_seen_at >= TOUCH_INTERVAL.as_secs() as i64 {
+            let id = session.id;
+            self.db.write(move |db| db.execute("update sessions set last_seen_at = ?1 where id = ?2;", params![now, id])).await?;
+            session.last_seen_at = now;
+        }
+        Ok(Some(session))
+    }
+
+    async fn log_in(&self, hash: &str, new_hash: &str, user: &User, adopt_history: bool, client: &Client) -> Result<usize> {
+        let (hash, new_hash, user_id, name, client) = (hash.to_owned(), new_hash.to_owned(), user.id, user.name.clone(), client.clone());
+        self.db.write(move |db| {
+            let tx = db.transaction()?;
+            tx.execute("insert into sessions (hash, is_auth, user_id, name, created_at, last_seen_at, user_agent, ip) values (?1, true, ?2, ?3, unixepoch(), unixepoch(), ?4, ?5);",
+                params![&new_hash, user_id, &name, &client.user_agent, &client.ip])?;
+            let new_id = tx.last_insert_rowid();
+            let adopted = if adopt_history {
+                tx.execute("update calculations set user_id = ?1, session_id = ?2 where user_id is null and session_id = (select id from sessions where hash = ?3);",
+                    params![user_id, new_id, &hash])?
+            } else {
+                0
+            };
+            tx.execute("update sessions set revoked_at = unixepoch() where hash = ?1 and revoked_at is null;", [&hash])?;
+            tx.commit()?;
+            Ok(adopted)
+        }).await
+    }
+
+    async fn revoke(&self, hash: &str) -> Result<bool> {
+        let hash = hash.to_owned();
+        self.db.write(move |db| {
+            Ok(db.execute("update sessions set revoked_at = unixepoch() where hash = ?1 and revoked_at is null;", [&hash])? > 0)
+        }).await
+    }
+
+    async fn revoke_all(&self, user_id: i32) -> Result<usize> {
+        self.db.write(move |db| {
+            db.execute("update sessions set revoked_at = unixepoch() where user_id = ?1 and revoked_at is null;", [user_id])
+        }).await
+    }
+
+    async fn revoke_for_user(&self, user_id: i32, id: i32) -> Result<bool> {
+        self.db.write(move |db| {
+            Ok(db.execute("update sessions set revoked_at = unixepoch() where id = ?1 and user_id = ?2 and revoked_at is null;", [id, user_id])? > 0)
+        }).await
+    }
+
+    async fn list_live(&self, user_id: i32, lifetimes: SessionLifetimes) -> Result<Vec<Session>> {
+        self.db.read(move |db| {
+            let mut stmt = db.prepare(&format!("select {SESSION_COLUMNS} from sessions where user_id = ?1 and revoked_at is null and created_at > unixepoch() - ?2 and last_seen_at > unixepoch() - ?3 order by last_seen_at desc, id desc;"))?;
+            let sessions = stmt.query_map(params![user_id, lifetimes.absolute.as_secs(), lifetimes.idle.as_secs()], session_from_row)?;
+            sessions.collect()
+        }).await
+    }
+
+    async fn purge_expired(&self, lifetimes: SessionLifetimes) -> Result<usize> {
+        self.db.write(move |db| {
+            let tx = db.transaction()?;
+            let dead = "select id from sessions where revoked_at is not null or created_at <= unixepoch() - ?1 or last_seen_at <= unixepoch() - ?2";
+            let limits = params![lifetimes.absolute.as_secs(), lifetimes.idle.as_secs()];
+            tx.execute(&format!("delete from calculations where user_id is null and session_id in ({dead});"), limits)?;
+            tx.execute(&format!("update calculations set session_id = null where session_id in ({dead});"), limits)?;
+            let purged = tx.execute(&format!("delete from sessions where id in ({dead});"), limits)?;
+            tx.commit()?;
+            Ok(purged)
+        }).await
+    }
+}
+
+#[async_trait]
+impl CalculationRepository for SqliteStore {
+    async fn insert(&self, calculation: NewCalculation) -> Result<i32> {
+        self.db.write(move |db| {
+            db.execute("insert into calculations (num1, num2, operator_id, result, session_id, user_id, expression, mode, exact_result, created_at) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, unixepoch());", params![
+                calculation.num1.map(real),
+                calculation.num2.map(real),
+                calculation.operator_id,
+                real(calculation.result),
+                calculation.session_id,
+                calculation.user_id,
+                calculation.expression,
+                calculation.mode.as_str(),
+                calculation.exact_result,
+            ])?;
+            Ok(db.last_insert_rowid() as i32)
+        }).await
+    }
+
+    async fn page(&self, owner: Owner, filter: &HistoryFilter) -> Result<Page> {
+        let (owner_condition, owner_id) = owner_condition(owner);
+        let mut conditions = vec![owner_condition, "deleted_at is null"];
+        let mut values = vec![Value::from(owner_id)];
+
+        if let Some(operator_id) = filter.operator_id {
+            conditions.push("operator_id = ?");
+            values.push(operator_id.into());
+        }
+        if let Some(from) = filter.from {
+            conditions.push("created_at >= ?");
+            values.push(from.into());
+        }
+        if let Some(to) = filter.to {
+            conditions.push("created_at < ?");
+            values.push(to.into());
+        }
+        // Leave out NaN, stored as text, which no bound admits.
+        if let Some(min_result) = filter.min_result {
+            conditions.push("result >= ? and typeof(result) = 'real'");
+            values.push(min_result.into());
+        }
+        if let Some(max_result) = filter.max_result {
+            conditions.push("result <= ? and typeof(result) = 'real'");
+            values.push(max_result.into());
+        }
+
+        let column = filter.sort.column();
+        let (direction, comparison) = match filter.order {
+            Order::Asc => ("asc", ">"),
+            Order::Desc => ("desc", "<"),
+        };
+        let after = match filter.after {
+            Some(Cursor::CreatedAt { created_at, id }) => Some((Value::from(created_at), id)),
+            Some(Cursor::Result { result, id }) => Some((real(result), id)),
+            None => None,
+        };
+        let cursor_condition = format!("({column}, id) {comparison} (?, ?)");
+        if let Some((key, id)) = after {
+            conditions.push(&cursor_condition);
+            values.push(key);
+            values.push(id.into());
+        }
+        values.push(i64::from(filter.limit + 1).into());
+
+        let sql = format!(
+            "select {CALCULATION_COLUMNS} from calculations where {} order by {column} {direction}, id {direction} limit ?;",
+            conditions.join(" and ")
+        );
+        let rows = self.db.read(move |db| {
+            let mut stmt = db.prepare(&sql)?;
+            let rows = stmt.query_map(params_from_iter(values), calculation_from_row)?;
+            rows.collect::<rusqlite::Result<Vec<Calculation>>>()
+        }).await?;
+
+        Ok(filter.page(rows))
+    }
+
+    async fn delete(&self, owner: Owner, id: i32) -> Result<bool> {
+        let (owner_condition, owner_id) = owner_condition(owner);
+        self.db.write(move |db| {
+            let sql = format!("update calculations set deleted_at = unixepoch() where id = ? and {owner_condition} and deleted_at is null;");
+            Ok(db.execute(&sql, [id, owner_id])? > 0)
+        }).await
+    }
+
+    async fn delete_all(&self, owner: Owner) -> Result<usize> {
+        let (owner_condition, owner_id) = owner_condition(owner);
+        self.db.write(move |db| {
+            db.execute(&format!("update calculations set deleted_at = unixepoch() where {owner_condition} and deleted_at is null;"), [owner_id])
+        }).await
+    }
+
+    async fn restore(&self, owner: Owner, undo_window: Duration) -> Result<usize> {
+        let (owner_condition, owner_id) = owner_condition(owner);
+        self.db.write(move |db| {
+            let sql = format!("update calculations set deleted_at = null where {owner_condition} and deleted_at > unixepoch() - ?;");
+            db.execute(&sql, params![owner_id, undo_window.as_secs()])
+        }).await
+    }
+
+    async fn purge_deleted(&self, undo_window: Duration) -> Result<usize> {
+        self.db.write(move |db| {
+            db.execute("delete from calculations where deleted_at <= unixepoch() - ?1;", [undo_window.as_secs()])
+        }).await
+    }
+}
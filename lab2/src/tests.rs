@@ -0,0 +1,656 @@
+//! End-to-end tests of `filters::site`, driven in-process with
+//! `warp::test::request` against a fresh SQLite database that `Database::open`
+//! migrates exactly as it does at startup.
+
+use std::sync::Arc;
+
+use calc_core::numeric::Limits;
+use proptest::prelude::*;
+use proptest::sample;
+use proptest::test_runner::TestRunner;
+use serde_json::{json, Value};
+use tempfile::TempDir;
+use warp::filters::BoxedFilter;
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::reply::{Reply, Response};
+use warp::Filter;
+
+use crate::config::{Config, PasswordsConfig};
+use crate::db::Database;
+use crate::filters;
+use crate::models::{self, SessionIssuer, SessionLifetimes};
+use crate::operators;
+use crate::password::Passwords;
+use crate::repository::Repositories;
+use crate::throttle::Throttle;
+
+struct App {
+    site: BoxedFilter<(Response,)>,
+    repos: Repositories,
+    passwords: Passwords,
+    /// Holds the database file until the test ends.
+    dir: TempDir,
+}
+
+impl App {
+    fn new() -> App {
+        App::with_database("")
+    }
+
+    /// An app over a database that `seed` has already set up, as an
+    /// existing deployment's would be before it is migrated.
+    fn with_database(seed: &str) -> App {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.database.path = dir.path().join("test.sqlitedb");
+        rusqlite::Connection::open(&config.database.path).unwrap().execute_batch(seed).unwrap();
+        config.passwords = PasswordsConfig { memory_kib: 8, iterations: 1, parallelism: 1 };
+
+        let db = Database::open(&config.database).unwrap();
+        let registry = Arc::new(operators::load(&db.writer().unwrap()).unwrap());
+        let issuer = Arc::new(SessionIssuer {
+            names: models::load_session_names(&config.server.static_root).unwrap(),
+            secure_cookie: false,
+        });
+        let passwords = Passwords::new(config.hash_cost()).unwrap();
+        let repos = Repositories::sqlite(db);
+        let throttle = Throttle::new(config.throttle_policy());
+        let site = filters::site(repos.clone(), issuer, registry, passwords.clone(), throttle, &config)
+            .map(Reply::into_response)
+            .boxed();
+        App { site, repos, passwords, dir }
+    }
+
+    /// A connection of its own to the app's database, to look behind the
+    /// repositories.
+    fn database(&self) -> rusqlite::Connection {
+        rusqlite::Connection::open(self.dir.path().join("test.sqlitedb")).unwrap()
+    }
+
+    /// The session cookie a first-time visitor is handed.
+    async fn visit(&self) -> String {
+        let response = warp::test::request().path("/").reply(&self.site).await;
+        session_cookie(&response).expect("a new visitor gets a session cookie")
+    }
+
+    async fn send(&self, cookie: &str, method: &str, path: &str, body: Option<Value>) -> warp::http::Response<Bytes> {
+        let mut request = warp::test::request().method(method).path(path).header("cookie", format!("session_hash={cookie}"));
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        request.reply(&self.site).await
+    }
+
+    async fn call(&self, cookie: &str, method: &str, path: &str, body: Option<Value>) -> (StatusCode, Value) {
+        let response = self.send(cookie, method, path, body).await;
+        let body = if response.body().is_empty() { Value::Null } else { serde_json::from_slice(response.body()).unwrap() };
+        (response.status(), body)
+    }
+
+    /// A visitor logged in as a new user with `role`.
+    async fn user(&self, name: &str, role: &str) -> String {
+        let auth_hash = self.passwords.hash("secret").unwrap();
+        self.repos.users.create(name, &auth_hash, role).await.unwrap().unwrap();
+        let cookie = self.visit().await;
+        self.log_in(&cookie, "/api/login", json!({ "name": name, "password": "secret" })).await
+    }
+
+    /// Logs `cookie` in through `path`, `/api/login` or `/api/register`,
+    /// and returns the session cookie handed out in its place.
+    async fn log_in(&self, cookie: &str, path: &str, credentials: Value) -> String {
+        let response = self.send(cookie, "POST", path, Some(credentials)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        session_cookie(&response).expect("logging in hands out a new session")
+    }
+}
+
+fn session_cookie(response: &warp::http::Response<Bytes>) -> Option<String> {
+    let header = response.headers().get("set-cookie")?.to_str().ok()?;
+    let token = header.strip_prefix("session_hash=")?.split(';').next()?;
+    Some(token.to_string())
+}
+
+fn code(body: &Value) -> &str {
+    body["error"]["code"].as_str().unwrap_or_default()
+}
+
+#[tokio::test]
+async fn visitors_without_a_live_session_get_a_new_one() {
+    let app = App::new();
+    let cookie = app.visit().await;
+
+    let (status, session) = app.call(&cookie, "GET", "/api/session_info", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(session["is_auth"], false);
+
+    let response = app.send("forged", "GET", "/", None).await;
+    let fresh = session_cookie(&response).expect("an unknown cookie is replaced");
+    assert_ne!(fresh, cookie);
+    let (status, body) = app.call("forged", "GET", "/api/session_info", None).await;
+    assert_eq!((status, code(&body)), (StatusCode::UNAUTHORIZED, "session_not_found"));
+    let response = warp::test::request().path("/api/session_info").reply(&app.site).await;
+    let body: Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!((response.status(), code(&body)), (StatusCode::UNAUTHORIZED, "session_not_found"));
+}
+
+#[tokio::test]
+async fn sessions_are_written_back_at_most_once_a_minute() {
+    let app = App::new();
+    let cookie = app.visit().await;
+    // The visit made the only session there is.
+    let last_seen = |age: i64| {
+        let db = app.database();
+        db.execute("update sessions set last_seen_at = unixepoch() - ?1;", [age]).unwrap();
+        move || db.query_row("select unixepoch() - last_seen_at from sessions;", [], |row| row.get::<_, i64>(0)).unwrap()
+    };
+
+    let age = last_seen(30);
+    let (status, _) = app.call(&cookie, "GET", "/api/session_info", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(age() >= 30, "a session seen 30 s ago was written back");
+
+    let age = last_seen(90);
+    let (status, _) = app.call(&cookie, "GET", "/api/session_info", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(age() < 30, "a session seen 90 s ago was not written back");
+}
+
+/// The schema of `database.sqlitedb` as it was before migrations existed,
+/// with rows left by users deleted while foreign keys were not enforced.
+const BASELINE_SCHEMA: &str = "
+    pragma foreign_keys = off;
+    create table users(id integer primary key autoincrement, name string not null, auth_hash string not null, role);
+    create table sessions(id integer primary key autoincrement, hash string not null, is_auth bool not null, user_id int, name string,
+    foreign key(user_id) references users(id));
+    create table operators(id integer primary key autoincrement, name string);
+    create table calculations(id integer primary key autoincrement, num1 float not null,
+    num2 float not null,
+    operator_id int not null,
+    result float not null,
+    session_id int not null,
+    user_id int,
+    foreign key(user_id) references users(id),
+    foreign key(session_id) references sessions(id),
+    foreign key(operator_id) references operators(id)
+    );
+    insert into operators(id, name) values (1, 'Addition'), (2, 'Subtraction'), (3, 'Multiplication'), (4, 'Division');
+    insert into users(id, name, auth_hash, role) values (1, 'ann', 'ann:secret', null);
+    insert into sessions(id, hash, is_auth, user_id, name) values (1, 'old-cookie', true, 1, 'ann');
+    insert into sessions(id, hash, is_auth, user_id, name) values (2, 'gone-cookie', true, 2, 'deleted');
+    insert into calculations(num1, num2, operator_id, result, session_id, user_id) values (1, 2, 1, 3, 1, 1);
+    insert into calculations(num1, num2, operator_id, result, session_id, user_id) values (2, 2, 3, 4, 2, 2);
+";
+
+#[tokio::test]
+async fn databases_from_before_migrations_are_upgraded() {
+    let app = App::with_database(BASELINE_SCHEMA);
+    let cookie = app.visit().await;
+
+    let (status, body) = app.call(&cookie, "POST", "/api/calculate", Some(json!({ "num1": 2, "num2": 3, "operator_id": 3 }))).await;
+    assert_eq!((status, body["result"].clone()), (StatusCode::OK, json!(6.0)));
+    let (status, body) = app.call(&cookie, "POST", "/api/evaluate", Some(json!({ "expression": "1 + 2 * 3" }))).await;
+    assert_eq!((status, body["result"].clone()), (StatusCode::OK, json!(7.0)));
+    let (status, body) = app.call(&cookie, "GET", "/api/history", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["history"].as_array().map(Vec::len), Some(2));
+
+    let cookie = app.log_in(&cookie, "/api/login", json!({ "name": "ann", "password": "secret" })).await;
+    let (status, body) = app.call(&cookie, "GET", "/api/history", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["history"].as_array().map(Vec::len), Some(3));
+}
+
+#[tokio::test]
+async fn register_login_and_logout() {
+    let app = App::new();
+    let credentials = json!({ "name": "ann", "password": "secret" });
+
+    let anonymous = app.visit().await;
+    let ann = app.log_in(&anonymous, "/api/register", credentials.clone()).await;
+    assert_revoked(&app, &anonymous).await;
+    let (_, session) = app.call(&ann, "GET", "/api/session_info", None).await;
+    assert_eq!((session["is_auth"].clone(), session["name"].clone()), (json!(true), json!("ann")));
+
+    let other = app.visit().await;
+    let (status, body) = app.call(&other, "POST", "/api/register", Some(credentials.clone())).await;
+    assert_eq!((status, code(&body)), (StatusCode::CONFLICT, "conflict"));
+    let (status, body) = app.call(&other, "POST", "/api/login", Some(json!({ "name": "ann", "password": "wrong" }))).await;
+    assert_eq!((status, code(&body)), (StatusCode::UNAUTHORIZED, "invalid_credentials"));
+    let (status, body) = app.call(&other, "POST", "/api/login", Some(json!({ "name": "ann" }))).await;
+    assert_eq!((status, code(&body)), (StatusCode::BAD_REQUEST, "bad_request"));
+    let (status, body) = app.call(&other, "POST", "/api/login", Some(credentials)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["user_id"], session["user_id"]);
+
+    // A cross-site link or image must not be able to log anyone out.
+    let (status, body) = app.call(&ann, "GET", "/api/logout", None).await;
+    assert_eq!((status, code(&body)), (StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed"));
+    let response = app.send(&ann, "POST", "/api/logout", None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let fresh = session_cookie(&response).expect("logout hands out a new session");
+    let (_, session) = app.call(&fresh, "GET", "/api/session_info", None).await;
+    assert_eq!(session["is_auth"], false);
+    assert_revoked(&app, &ann).await;
+}
+
+/// A cookie somebody planted before login, or read off the machine, is no
+/// use once the visitor logs in: they get a new one and keep their history.
+#[tokio::test]
+async fn logging_in_replaces_the_session_cookie() {
+    let app = App::new();
+    app.user("ann", "normise").await;
+    let anonymous = app.visit().await;
+    let (status, _) = app.call(&anonymous, "POST", "/api/calculate", Some(json!({ "num1": 2, "num2": 3, "operator_id": 3 }))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let ann = app.log_in(&anonymous, "/api/login", json!({ "name": "ann", "password": "secret" })).await;
+    assert_ne!(ann, anonymous);
+    assert_revoked(&app, &anonymous).await;
+    let (_, session) = app.call(&ann, "GET", "/api/session_info", None).await;
+    assert_eq!((session["is_auth"].clone(), session["name"].clone()), (json!(true), json!("ann")));
+    let (_, body) = app.call(&ann, "GET", "/api/history", None).await;
+    let history = body["history"].as_array().unwrap();
+    assert_eq!((history.len(), history[0]["session_id"].clone()), (1, session["id"].clone()));
+}
+
+#[tokio::test]
+async fn purging_drops_dead_sessions_but_keeps_account_history() {
+    let app = App::new();
+    let calculation = json!({ "num1": 2, "num2": 3, "operator_id": 3 });
+    let ann = app.user("ann", "normise").await;
+    let idle = app.visit().await;
+    let live = app.visit().await;
+    for cookie in [&ann, &idle] {
+        let (status, _) = app.call(cookie, "POST", "/api/calculate", Some(calculation.clone())).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    let (_, session) = app.call(&idle, "GET", "/api/session_info", None).await;
+    let idle_for = SessionLifetimes::default().idle.as_secs() + 1;
+    app.database().execute("update sessions set last_seen_at = unixepoch() - ?1 where id = ?2;", (idle_for, session["id"].as_i64())).unwrap();
+    assert_eq!(app.send(&ann, "POST", "/api/logout", None).await.status(), StatusCode::OK);
+
+    // Ann's session from before login, her logged-in one and the idle one.
+    assert_eq!(app.repos.sessions.purge_expired(SessionLifetimes::default()).await.unwrap(), 3);
+    let db = app.database();
+    assert_eq!(db.query_row("select count(*) from sessions;", [], |row| row.get::<_, i64>(0)).unwrap(), 2);
+    let calculations = db.query_row("select count(*), count(session_id) from calculations;", [], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))).unwrap();
+    assert_eq!(calculations, (1, 0));
+
+    let (status, _) = app.call(&live, "GET", "/api/session_info", None).await;
+    assert_eq!(status, StatusCode::OK);
+    let ann = app.log_in(&live, "/api/login", json!({ "name": "ann", "password": "secret" })).await;
+    let (_, body) = app.call(&ann, "GET", "/api/history", None).await;
+    assert_eq!(body["history"][0]["session_id"], Value::Null);
+}
+
+/// `cookie` no longer names a session: pages replace it like a forged one,
+/// and endpoints that need a session refuse it.
+async fn assert_revoked(app: &App, cookie: &str) {
+    let response = app.send(cookie, "GET", "/", None).await;
+    assert!(session_cookie(&response).is_some(), "a revoked cookie is replaced");
+    for (method, path, body) in [("GET", "/api/session_info", None), ("POST", "/api/calculate", Some(json!({ "num1": 1, "num2": 2, "operator_id": 1 })))] {
+        let (status, body) = app.call(cookie, method, path, body).await;
+        assert_eq!((status, code(&body)), (StatusCode::UNAUTHORIZED, "session_not_found"), "{path} accepted a revoked cookie");
+    }
+}
+
+#[tokio::test]
+async fn logout_everywhere_revokes_every_session_of_the_user() {
+    let app = App::new();
+    let laptop = app.user("ann", "normise").await;
+    let phone = app.log_in(&app.visit().await, "/api/login", json!({ "name": "ann", "password": "secret" })).await;
+    let bob = app.user("bob", "normise").await;
+
+    let anonymous = app.visit().await;
+    let (status, body) = app.call(&anonymous, "POST", "/api/logout_everywhere", None).await;
+    assert_eq!((status, code(&body)), (StatusCode::UNAUTHORIZED, "not_logged_in"));
+
+    let response = app.send(&phone, "POST", "/api/logout_everywhere", None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(serde_json::from_slice::<Value>(response.body()).unwrap()["revoked"], 2);
+    let fresh = session_cookie(&response).expect("logging out everywhere hands out a new session");
+    let (_, session) = app.call(&fresh, "GET", "/api/session_info", None).await;
+    assert_eq!(session["is_auth"], false);
+    assert_revoked(&app, &laptop).await;
+    assert_revoked(&app, &phone).await;
+
+    let (_, session) = app.call(&bob, "GET", "/api/session_info", None).await;
+    assert_eq!(session["is_auth"], true);
+}
+
+#[tokio::test]
+async fn calculate_fills_and_delete_history_clears_history() {
+    let app = App::new();
+    let cookie = app.visit().await;
+
+    for (num1, num2) in [(1, 2), (3, 4)] {
+        let (status, body) = app.call(&cookie, "POST", "/api/calculate", Some(json!({ "num1": num1, "num2": num2, "operator_id": 3 }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["result"], json!(f64::from(num1 * num2)));
+    }
+    let (status, body) = app.call(&cookie, "POST", "/api/calculate", Some(json!({ "num1": 1, "num2": 2, "operator_id": 42 }))).await;
+    assert_eq!((status, code(&body)), (StatusCode::BAD_REQUEST, "bad_request"));
+    let (status, body) = app.call(&cookie, "POST", "/api/calculate", Some(json!({ "num1": 1, "num2": 0, "operator_id": 4 }))).await;
+    assert_eq!((status, code(&body)), (StatusCode::UNPROCESSABLE_ENTITY, "division_by_zero"));
+    let (status, body) = app.call(&cookie, "POST", "/api/calculate", Some(json!({ "num1": 1 }))).await;
+    assert_eq!((status, code(&body)), (StatusCode::BAD_REQUEST, "bad_request"));
+
+    let (status, history) = app.call(&cookie, "GET", "/api/history", None).await;
+    assert_eq!(status, StatusCode::OK);
+    let expressions: Vec<_> = history["history"].as_array().unwrap().iter().map(|row| row["expression"].clone()).collect();
+    assert_eq!(expressions, [json!("3 * 4"), json!("1 * 2")]);
+    let (status, _) = app.call(&cookie, "GET", "/api/history?limit=0", None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let stranger = app.visit().await;
+    let (_, history) = app.call(&stranger, "GET", "/api/history", None).await;
+    assert_eq!(history["history"], json!([]));
+    let (_, deleted) = app.call(&stranger, "POST", "/api/delete_history", None).await;
+    assert_eq!(deleted["deleted"], 0);
+
+    let (status, deleted) = app.call(&cookie, "POST", "/api/delete_history", None).await;
+    assert_eq!((status, deleted["deleted"].clone()), (StatusCode::OK, json!(2)));
+    let (_, history) = app.call(&cookie, "GET", "/api/history", None).await;
+    assert_eq!(history["history"], json!([]));
+    let (_, restored) = app.call(&cookie, "POST", "/api/history/undo", None).await;
+    assert_eq!(restored["restored"], 2);
+}
+
+/// SQLite turns a NaN double into NULL, so the repository stores it as text;
+/// this runs that path, which `Repositories::memory()` does not have.
+#[tokio::test]
+async fn non_finite_results_survive_the_database() {
+    let app = App::new();
+    let cookie = app.visit().await;
+
+    for (num1, num2, operator_id, result) in [(json!(-1), 0, 4, json!("-inf")), (json!("inf"), 0, 3, json!("NaN")), (json!(2), 3, 3, json!(6.0)), (json!(1), 0, 4, json!("inf"))] {
+        let body = json!({ "num1": num1, "num2": num2, "operator_id": operator_id, "ieee": true });
+        let (status, body) = app.call(&cookie, "POST", "/api/calculate", Some(body)).await;
+        assert_eq!((status, body["result"].clone()), (StatusCode::OK, result));
+    }
+    let stored: Vec<String> = app.database().prepare("select typeof(result) from calculations order by id").unwrap()
+        .query_map([], |row| row.get(0)).unwrap().collect::<Result<_, _>>().unwrap();
+    assert_eq!(stored, ["real", "text", "real", "real"]);
+
+    let results = |history: &Value| -> Vec<Value> {
+        history["history"].as_array().unwrap().iter().map(|row| row["result"].clone()).collect()
+    };
+    let (_, history) = app.call(&cookie, "GET", "/api/history?sort=result&order=asc", None).await;
+    assert_eq!(results(&history), [json!("-inf"), json!(6.0), json!("inf"), json!("NaN")]);
+    let (_, history) = app.call(&cookie, "GET", "/api/history?sort=result&order=asc&limit=2", None).await;
+    let cursor = history["next_cursor"].as_str().unwrap().to_string();
+    let (_, history) = app.call(&cookie, "GET", &format!("/api/history?sort=result&order=asc&cursor={cursor}"), None).await;
+    assert_eq!(results(&history), [json!("inf"), json!("NaN")]);
+    let (_, history) = app.call(&cookie, "GET", "/api/history?sort=result&min_result=0", None).await;
+    assert_eq!(results(&history), [json!("inf"), json!(6.0)]);
+    let (_, history) = app.call(&cookie, "GET", "/api/history?sort=result&max_result=10", None).await;
+    assert_eq!(results(&history), [json!(6.0), json!("-inf")]);
+}
+
+#[tokio::test]
+async fn only_moderlings_list_and_delete_users() {
+    let app = App::new();
+    let anonymous = app.visit().await;
+    let (status, body) = app.call(&anonymous, "GET", "/api/get_users", None).await;
+    assert_eq!((status, code(&body)), (StatusCode::UNAUTHORIZED, "not_logged_in"));
+
+    let ann = app.user("ann", "normise").await;
+    let (status, body) = app.call(&ann, "GET", "/api/get_users", None).await;
+    assert_eq!((status, code(&body)), (StatusCode::FORBIDDEN, "forbidden"));
+    let (status, body) = app.call(&ann, "POST", "/api/delete_user", None).await;
+    assert_eq!((status, code(&body)), (StatusCode::FORBIDDEN, "forbidden"));
+    let (_, session) = app.call(&ann, "GET", "/api/session_info", None).await;
+    let ann_id = session["user_id"].as_i64().unwrap();
+    app.call(&ann, "POST", "/api/calculate", Some(json!({ "num1": 1, "num2": 2, "operator_id": 1 }))).await;
+
+    let root = app.user("root", "moderling").await;
+    let (status, body) = app.call(&root, "GET", "/api/get_users", None).await;
+    assert_eq!(status, StatusCode::OK);
+    let names: Vec<_> = body["users"].as_array().unwrap().iter().map(|user| user["name"].clone()).collect();
+    assert_eq!(names, [json!("ann"), json!("root")]);
+    assert!(body["users"][0].get("auth_hash").is_none());
+
+    let delete = |user_id: &str| {
+        warp::test::request().method("POST").path("/api/delete_user")
+            .header("cookie", format!("session_hash={root}"))
+            .header("user_id", user_id)
+    };
+    assert_eq!(delete(&ann_id.to_string()).reply(&app.site).await.status(), StatusCode::OK);
+    assert_eq!(delete(&ann_id.to_string()).reply(&app.site).await.status(), StatusCode::NOT_FOUND);
+    assert_eq!(delete("ann").reply(&app.site).await.status(), StatusCode::BAD_REQUEST);
+    let (status, body) = app.call(&root, "POST", "/api/delete_user", None).await;
+    assert_eq!((status, code(&body)), (StatusCode::BAD_REQUEST, "bad_request"));
+    assert_revoked(&app, &ann).await;
+}
+
+#[tokio::test]
+async fn password_hashes_are_only_exported_on_request() {
+    let app = App::new();
+    let root = app.user("root", "moderling").await;
+    app.user("ann", "normise").await;
+
+    let (status, body) = app.call(&root, "GET", "/api/export_users", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["users"].as_array().map(Vec::len), Some(2));
+    assert!(body["users"].as_array().unwrap().iter().all(|user| user.get("auth_hash").is_none()));
+
+    let (status, dump) = app.call(&root, "GET", "/api/export_users?include_hashes=true", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(dump["users"][1]["auth_hash"].as_str().unwrap().starts_with("$argon2id$"));
+
+    // The dump restores the accounts elsewhere, passwords and all.
+    let restored = App::new();
+    let admin = restored.user("admin", "moderling").await;
+    let (status, report) = restored.call(&admin, "POST", "/api/import_users", Some(dump)).await;
+    assert_eq!((status, report["created"].clone()), (StatusCode::OK, json!(2)));
+    restored.log_in(&restored.visit().await, "/api/login", json!({ "name": "ann", "password": "secret" })).await;
+}
+
+#[tokio::test]
+async fn importing_a_new_hash_or_role_revokes_the_users_sessions() {
+    let app = App::new();
+    let root = app.user("root", "moderling").await;
+    let ann = app.user("ann", "normise").await;
+    let bob = app.user("bob", "normise").await;
+    let (_, dump) = app.call(&root, "GET", "/api/export_users?include_hashes=true", None).await;
+
+    // Rows that change nothing leave the sessions alone.
+    let (status, report) = app.call(&root, "POST", "/api/import_users?on_conflict=overwrite", Some(dump.clone())).await;
+    assert_eq!((status, report["updated"].clone()), (StatusCode::OK, json!(3)));
+    for cookie in [&root, &ann, &bob] {
+        let (_, session) = app.call(cookie, "GET", "/api/session_info", None).await;
+        assert_eq!(session["is_auth"], true);
+    }
+
+    let users = json!({ "users": [
+        { "name": "ann", "auth_hash": app.passwords.hash("reset").unwrap(), "role": "normise" },
+        { "name": "bob", "auth_hash": dump["users"][2]["auth_hash"], "role": "moderling" },
+    ] });
+    let (status, report) = app.call(&root, "POST", "/api/import_users?on_conflict=overwrite", Some(users)).await;
+    assert_eq!((status, report["updated"].clone()), (StatusCode::OK, json!(2)));
+    assert_revoked(&app, &ann).await;
+    assert_revoked(&app, &bob).await;
+    let (_, session) = app.call(&root, "GET", "/api/session_info", None).await;
+    assert_eq!(session["is_auth"], true);
+    app.log_in(&app.visit().await, "/api/login", json!({ "name": "ann", "password": "reset" })).await;
+}
+
+#[tokio::test]
+async fn unknown_endpoints_and_methods_are_json_errors() {
+    let app = App::new();
+    let cookie = app.visit().await;
+    let (status, body) = app.call(&cookie, "GET", "/api/nothing_here", None).await;
+    assert_eq!((status, code(&body)), (StatusCode::NOT_FOUND, "not_found"));
+    let (status, body) = app.call(&cookie, "GET", "/api/calculate", None).await;
+    assert_eq!((status, code(&body)), (StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed"));
+}
+
+#[tokio::test]
+async fn users_list_and_revoke_their_own_sessions() {
+    let app = App::new();
+    let anonymous = app.visit().await;
+    let (status, body) = app.call(&anonymous, "GET", "/api/sessions", None).await;
+    assert_eq!((status, code(&body)), (StatusCode::UNAUTHORIZED, "not_logged_in"));
+
+    let phone = app.user("ann", "normise").await;
+    let lab_machine = app.visit().await;
+    let response = warp::test::request().method("POST").path("/api/login")
+        .header("cookie", format!("session_hash={lab_machine}"))
+        .header("user-agent", "LabBrowser/1.0")
+        .remote_addr("10.0.0.7:52100".parse().unwrap())
+        .json(&json!({ "name": "ann", "password": "secret" }))
+        .reply(&app.site).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let lab_machine = session_cookie(&response).expect("logging in hands out a new session");
+
+    let (status, body) = app.call(&phone, "GET", "/api/sessions", None).await;
+    assert_eq!(status, StatusCode::OK);
+    let sessions = body["sessions"].as_array().unwrap();
+    assert_eq!(sessions.len(), 2);
+    let lab = sessions.iter().find(|session| session["current"] == false).unwrap();
+    assert_eq!((lab["user_agent"].clone(), lab["ip"].clone()), (json!("LabBrowser/1.0"), json!("10.0.0.7")));
+    assert!(lab.get("hash").is_none());
+    let lab_id = lab["id"].as_i64().unwrap();
+
+    let bob = app.user("bob", "normise").await;
+    let (status, body) = app.call(&bob, "DELETE", &format!("/api/sessions/{lab_id}"), None).await;
+    assert_eq!((status, code(&body)), (StatusCode::NOT_FOUND, "not_found"));
+
+    let (status, body) = app.call(&phone, "DELETE", &format!("/api/sessions/{lab_id}"), None).await;
+    assert_eq!((status, body["revoked"].clone()), (StatusCode::OK, json!(1)));
+    assert_revoked(&app, &lab_machine).await;
+    let (_, body) = app.call(&phone, "GET", "/api/sessions", None).await;
+    assert_eq!(body["sessions"].as_array().unwrap().len(), 1);
+    let (status, _) = app.call(&phone, "DELETE", &format!("/api/sessions/{lab_id}"), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn repeated_login_failures_are_throttled() {
+    let app = App::new();
+    app.user("ann", "normise").await;
+    app.user("bob", "normise").await;
+    let login = |cookie: &str, address: &str, name: &str, password: &str| {
+        warp::test::request().method("POST").path("/api/login")
+            .header("cookie", format!("session_hash={cookie}"))
+            .remote_addr(address.parse().unwrap())
+            .json(&json!({ "name": name, "password": password }))
+    };
+    let limits = Config::default().login_limits;
+
+    // Guessing one account locks that account.
+    let guesser = app.visit().await;
+    for _ in 0..=limits.account_free_failures {
+        assert_eq!(login(&guesser, "10.0.0.7:52100", "ann", "guess").reply(&app.site).await.status(), StatusCode::UNAUTHORIZED);
+    }
+    let response = login(&guesser, "10.0.0.7:52100", "ann", "secret").reply(&app.site).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()["retry-after"], "1");
+    let body: Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(code(&body), "too_many_requests");
+
+    // Another account can still log in from the guesser's address.
+    let response = login(&app.visit().await, "10.0.0.7:52100", "bob", "secret").reply(&app.site).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Guessing across many names blocks the address instead.
+    app.user("carol", "normise").await;
+    let sprayer = app.visit().await;
+    for i in 0..limits.ip_free_failures {
+        let name = format!("nobody{i}");
+        assert_eq!(login(&sprayer, "10.0.0.8:52100", &name, "guess").reply(&app.site).await.status(), StatusCode::UNAUTHORIZED);
+    }
+    assert_eq!(login(&sprayer, "10.0.0.8:52100", "carol", "guess").reply(&app.site).await.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(login(&sprayer, "10.0.0.8:52100", "carol", "secret").reply(&app.site).await.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // The account the blocked address went for still logs in from elsewhere.
+    let response = login(&app.visit().await, "10.0.0.9:52100", "carol", "secret").reply(&app.site).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+/// Guesses sent together are counted as they arrive, not when their slow
+/// password checks finish, so parallelism does not buy extra tries.
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_login_failures_are_throttled() {
+    let app = App::new();
+    app.user("ann", "normise").await;
+    let guesser = app.visit().await;
+
+    let mut guesses = tokio::task::JoinSet::new();
+    for _ in 0..20 {
+        let request = warp::test::request().method("POST").path("/api/login")
+            .header("cookie", format!("session_hash={guesser}"))
+            .json(&json!({ "name": "ann", "password": "guess" }));
+        let site = app.site.clone();
+        guesses.spawn(async move { request.reply(&site).await.status() });
+    }
+    let mut answered = 0;
+    while let Some(status) = guesses.join_next().await {
+        let status = status.unwrap();
+        if status == StatusCode::UNAUTHORIZED {
+            answered += 1;
+        } else {
+            assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        }
+    }
+    assert_eq!(answered, Config::default().login_limits.account_free_failures + 1);
+}
+
+#[tokio::test]
+async fn user_names_are_unique() {
+    let app = App::new();
+    assert!(app.repos.users.create("ann", "unused", "normise").await.unwrap().is_some());
+    assert_eq!(app.repos.users.create("ann", "unused", "normise").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn duplicate_names_are_renamed_apart() {
+    let app = App::with_database(&format!("{BASELINE_SCHEMA}
+        insert into users(id, name, auth_hash, role) values
+            (10, 'ann', 'x', null), (11, 'ann#10', 'x', null), (12, 'bob', 'x', null), (13, 'bob', 'x', null);
+    "));
+    let names: Vec<_> = app.repos.users.list().await.unwrap().into_iter().map(|user| (user.id, user.name)).collect();
+    let expected = [(1, "ann"), (10, "ann#10-2"), (11, "ann#10"), (12, "bob"), (13, "bob#13")];
+    assert_eq!(names, expected.map(|(id, name)| (id, name.to_string())));
+}
+
+fn operand() -> impl Strategy<Value = Value> {
+    prop_oneof![
+        (-1e6..1e6f64).prop_map(|n| json!(n)),
+        (-1000i32..1000).prop_map(|n| json!(n)),
+        (-99_999i64..99_999, 0u32..=4).prop_map(|(n, point)| json!(format!("{n}e-{point}"))),
+    ]
+}
+
+/// Every operator lab2 lists, sent to lab2's `/api/calculate` by id and to
+/// lab1's `/api/v1/calculate` by symbol, gives the same result or the same
+/// error.
+#[test]
+fn lab1_agrees_with_lab2() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let app = App::new();
+    let lab1 = lab1::api::routes(Limits::default(), Config::default().server.body_limit);
+    let (cookie, operators) = runtime.block_on(async {
+        let cookie = app.visit().await;
+        let (_, body) = app.call(&cookie, "GET", "/api/operators", None).await;
+        (cookie, body["operators"].as_array().unwrap().clone())
+    });
+
+    let inputs = (operand(), operand(), sample::select(operators), sample::select(vec!["f64", "decimal", "rational"]));
+    TestRunner::default().run(&inputs, |(num1, num2, operator, mode)| runtime.block_on(async {
+        let lab2_body = json!({ "num1": num1, "num2": num2, "operator_id": operator["id"], "mode": mode });
+        let (status, lab2) = app.call(&cookie, "POST", "/api/calculate", Some(lab2_body)).await;
+        let lab1_body = json!({ "value1": num1, "value2": num2, "operation": operator["symbol"], "mode": mode });
+        let response = warp::test::request().method("POST").path("/api/v1/calculate").json(&lab1_body).reply(&lab1).await;
+        let lab1: Value = serde_json::from_slice(response.body()).unwrap();
+
+        prop_assert_eq!(status, response.status());
+        if status.is_success() {
+            prop_assert_eq!(&lab2["result"], &lab1["value"]);
+        } else {
+            prop_assert_eq!(code(&lab2), lab1["code"].as_str().unwrap());
+        }
+        Ok(())
+    })).unwrap();
+}
@@ -0,0 +1,304 @@
+//! Brute-force protection for `/api/login` and `/api/register`.
+//!
+//! Failed attempts are counted per client address and per account name.
+//! Each key may fail a few times for free; after that every further failure
+//! blocks it for `base_delay`, doubling with each failure up to `max_delay`.
+//! An account that reaches `lockout_failures` is locked for `lockout`
+//! instead. A blocked request is answered `429` with `Retry-After`.
+//!
+//! Every attempt counts as a failure as soon as it starts, so guesses sent
+//! in parallel are counted before any of them is answered. A successful
+//! login takes its own attempt back and clears the account's count, but
+//! keeps the address's earlier failures, so one valid account cannot be
+//! used to keep guessing others. Counts are forgotten `reset_after` the
+//! last failure, and live in memory only: a restart starts them over.
+//!
+//! Names that do not exist are counted like real ones, so that a `429`
+//! does not tell them apart. At most `max_tracked` keys are kept; past that
+//! the counts that block nothing make room first.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Thresholds from the `[login_limits]` config section.
+#[derive(Debug, Clone, Copy)]
+pub struct Policy {
+    pub ip_free_failures: u32,
+    pub account_free_failures: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub lockout_failures: u32,
+    pub lockout: Duration,
+    pub reset_after: Duration,
+    pub max_tracked: usize,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            ip_free_failures: 20,
+            account_free_failures: 5,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5 * 60),
+            lockout_failures: 20,
+            lockout: Duration::from_secs(15 * 60),
+            reset_after: Duration::from_secs(60 * 60),
+            max_tracked: 100_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Ip(String),
+    Account(String),
+}
+
+#[derive(Debug)]
+struct Entry {
+    failures: u32,
+    last_failure: Instant,
+    blocked_until: Instant,
+}
+
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    policy: Policy,
+    entries: Arc<Mutex<HashMap<Key, Entry>>>,
+}
+
+impl Throttle {
+    pub fn new(policy: Policy) -> Throttle {
+        Throttle { policy, entries: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Starts an attempt, or says how long the client must wait first.
+    ///
+    /// The attempt counts as failed from the start, in the same lock as the
+    /// check, so concurrent guesses cannot all get in before the first
+    /// failure is recorded. Call `Attempt::succeeded` to take it back.
+    pub fn begin(&self, ip: Option<&str>, account: Option<&str>) -> Result<Attempt, Duration> {
+        self.begin_at(ip, account, Instant::now())
+    }
+
+    /// Drops counts that are no longer blocking or remembered. Returns how
+    /// many were dropped.
+    pub fn prune(&self) -> usize {
+        let now = Instant::now();
+        let mut entries = self.entries();
+        let before = entries.len();
+        entries.retain(|_, entry| entry.blocked_until > now || now.duration_since(entry.last_failure) < self.policy.reset_after);
+        before - entries.len()
+    }
+
+    fn entries(&self) -> std::sync::MutexGuard<'_, HashMap<Key, Entry>> {
+        self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn begin_at(&self, ip: Option<&str>, account: Option<&str>, now: Instant) -> Result<Attempt, Duration> {
+        let mut entries = self.entries();
+        if let Some(wait) = wait(&entries, ip, account, now) {
+            return Err(wait);
+        }
+        let ip_blocked = ip.map(|ip| self.count_failure(&mut entries, Key::Ip(ip.to_owned()), now));
+        if let Some(account) = account {
+            self.count_failure(&mut entries, Key::Account(account.to_owned()), now);
+        }
+        Ok(Attempt {
+            throttle: self.clone(),
+            ip: ip.map(str::to_owned).zip(ip_blocked),
+            account: account.map(str::to_owned),
+        })
+    }
+
+    /// Returns when the key was blocked until before and after this failure.
+    fn count_failure(&self, entries: &mut HashMap<Key, Entry>, key: Key, now: Instant) -> (Instant, Instant) {
+        let (free_failures, locks) = match key {
+            Key::Ip(_) => (self.policy.ip_free_failures, false),
+            Key::Account(_) => (self.policy.account_free_failures, true),
+        };
+        if !entries.contains_key(&key) && entries.len() >= self.policy.max_tracked {
+            self.make_room(entries, now);
+        }
+        let entry = entries.entry(key).or_insert(Entry { failures: 0, last_failure: now, blocked_until: now });
+        if now.duration_since(entry.last_failure) >= self.policy.reset_after {
+            entry.failures = 0;
+        }
+        entry.failures += 1;
+        entry.last_failure = now;
+
+        let delay = if locks && entry.failures >= self.policy.lockout_failures {
+            self.policy.lockout
+        } else if entry.failures > free_failures {
+            let doublings = (entry.failures - free_failures - 1).min(31);
+            self.policy.base_delay.saturating_mul(1 << doublings).min(self.policy.max_delay)
+        } else {
+            Duration::ZERO
+        };
+        let before = entry.blocked_until;
+        entry.blocked_until = before.max(now + delay);
+        (before, entry.blocked_until)
+    }
+
+    /// Drops every count that blocks nothing right now, or if all of them
+    /// block, the one whose block ends first.
+    fn make_room(&self, entries: &mut HashMap<Key, Entry>, now: Instant) {
+        entries.retain(|_, entry| entry.blocked_until > now);
+        if entries.len() < self.policy.max_tracked {
+            return;
+        }
+        let first_free = entries.iter().min_by_key(|(_, entry)| entry.blocked_until).map(|(key, _)| key.clone());
+        if let Some(key) = first_free {
+            entries.remove(&key);
+        }
+    }
+}
+
+/// An attempt started by `Throttle::begin`. Dropping it leaves it counted
+/// as a failure.
+#[derive(Debug)]
+pub struct Attempt {
+    throttle: Throttle,
+    /// The address, and when it was blocked until before and after this
+    /// attempt was counted.
+    ip: Option<(String, (Instant, Instant))>,
+    account: Option<String>,
+}
+
+impl Attempt {
+    /// Clears the account's count and takes this attempt, with any delay
+    /// it caused, back from the address's.
+    pub fn succeeded(self) {
+        let mut entries = self.throttle.entries();
+        if let Some(account) = self.account {
+            entries.remove(&Key::Account(account));
+        }
+        if let Some((ip, (before, after))) = self.ip {
+            if let Some(entry) = entries.get_mut(&Key::Ip(ip)) {
+                entry.failures = entry.failures.saturating_sub(1);
+                // Unless a later failure has blocked it since.
+                if entry.blocked_until == after {
+                    entry.blocked_until = before;
+                }
+            }
+        }
+    }
+}
+
+fn wait(entries: &HashMap<Key, Entry>, ip: Option<&str>, account: Option<&str>, now: Instant) -> Option<Duration> {
+    keys(ip, account)
+        .filter_map(|key| entries.get(&key))
+        .filter_map(|entry| entry.blocked_until.checked_duration_since(now))
+        .filter(|wait| !wait.is_zero())
+        .max()
+}
+
+fn keys(ip: Option<&str>, account: Option<&str>) -> impl Iterator<Item = Key> {
+    let ip = ip.map(|ip| Key::Ip(ip.to_owned()));
+    let account = account.map(|account| Key::Account(account.to_owned()));
+    ip.into_iter().chain(account)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle() -> Throttle {
+        Throttle::new(Policy {
+            ip_free_failures: 3,
+            account_free_failures: 2,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(8),
+            lockout_failures: 8,
+            lockout: Duration::from_secs(600),
+            reset_after: Duration::from_secs(3600),
+            max_tracked: 4,
+        })
+    }
+
+    fn secs(secs: u64) -> Option<Duration> {
+        Some(Duration::from_secs(secs))
+    }
+
+    fn wait_at(throttle: &Throttle, ip: Option<&str>, account: Option<&str>, now: Instant) -> Option<Duration> {
+        wait(&throttle.entries(), ip, account, now)
+    }
+
+    #[test]
+    fn backs_off_exponentially_then_locks_the_account() {
+        let throttle = throttle();
+        let start = Instant::now();
+        let waits: Vec<_> = (0..8).map(|i| {
+            let now = start + Duration::from_secs(10 * i);
+            throttle.begin_at(None, Some("ann"), now).unwrap();
+            wait_at(&throttle, None, Some("ann"), now)
+        }).collect();
+        assert_eq!(waits, [None, None, secs(1), secs(2), secs(4), secs(8), secs(8), secs(600)]);
+        let last = start + Duration::from_secs(70);
+        assert_eq!(throttle.begin_at(None, Some("ann"), last).unwrap_err(), Duration::from_secs(600));
+        assert!(throttle.begin_at(None, Some("bob"), last).is_ok());
+        assert!(throttle.begin_at(None, Some("ann"), last + Duration::from_secs(600)).is_ok());
+    }
+
+    #[test]
+    fn limits_addresses_across_accounts() {
+        let throttle = throttle();
+        let now = Instant::now();
+        for name in ["a", "b", "c", "d"] {
+            throttle.begin_at(Some("10.0.0.1"), Some(name), now).unwrap();
+        }
+        assert_eq!(wait_at(&throttle, Some("10.0.0.1"), Some("e"), now), secs(1));
+        assert_eq!(wait_at(&throttle, Some("10.0.0.2"), Some("a"), now), None);
+
+        // Success takes back its own attempt but not the earlier failures.
+        let later = now + Duration::from_secs(1);
+        throttle.begin_at(Some("10.0.0.1"), Some("e"), later).unwrap().succeeded();
+        assert_eq!(wait_at(&throttle, Some("10.0.0.1"), Some("f"), later), None);
+        throttle.begin_at(Some("10.0.0.1"), Some("f"), later).unwrap();
+        assert_eq!(wait_at(&throttle, Some("10.0.0.1"), Some("f"), later), secs(2));
+    }
+
+    #[test]
+    fn forgets_old_failures() {
+        let throttle = throttle();
+        let start = Instant::now();
+        for _ in 0..3 {
+            throttle.begin_at(None, Some("ann"), start).unwrap();
+        }
+        let later = start + Duration::from_secs(3600);
+        throttle.begin_at(None, Some("ann"), later).unwrap();
+        assert_eq!(wait_at(&throttle, None, Some("ann"), later), None);
+    }
+
+    #[test]
+    fn concurrent_attempts_count_before_they_finish() {
+        let throttle = throttle();
+        let barrier = std::sync::Barrier::new(32);
+        let started = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..32).map(|_| scope.spawn(|| {
+                barrier.wait();
+                throttle.begin(Some("10.0.0.1"), Some("ann")).ok()
+            })).collect();
+            // Hold every attempt open until all have been tried.
+            let attempts: Vec<_> = threads.into_iter().map(|thread| thread.join().unwrap()).collect();
+            attempts.iter().filter(|attempt| attempt.is_some()).count()
+        });
+        assert_eq!(started, 3);
+    }
+
+    #[test]
+    fn unknown_names_cannot_grow_the_map_past_its_cap() {
+        let throttle = throttle();
+        let start = Instant::now();
+        for _ in 0..3 {
+            throttle.begin_at(None, Some("ann"), start).unwrap();
+        }
+        for i in 0..100 {
+            let now = start + Duration::from_millis(i);
+            throttle.begin_at(None, Some(&format!("nobody{i}")), now).unwrap();
+            assert!(throttle.entries().len() <= 4);
+        }
+        assert_eq!(wait_at(&throttle, None, Some("ann"), start + Duration::from_millis(100)), Some(Duration::from_millis(900)));
+    }
+}